@@ -1,22 +1,59 @@
 #![forbid(unsafe_code)]
 #![allow(dead_code)]
 
+mod date;
+mod parse;
+mod render;
+mod router;
+mod storage;
+
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     env, fmt,
-    fs::{read, write},
-    io::{BufRead, BufReader, Read, Write},
-    net::{TcpListener, TcpStream},
+    fs::create_dir_all,
+    io::{self, BufRead, BufReader, Cursor, ErrorKind, Read, Seek, SeekFrom, Write},
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream},
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
     str::FromStr,
-    sync::Arc,
-    thread,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, Sender, SyncSender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+use date::{format_http_date, parse_http_date};
+use parse::{
+    parse_status_line, read_body, read_headers, read_line_limited, reject_nul, Limits, ParseError,
+};
+use render::{html_escape, json_string, Render, Representation};
+use router::{HttpError, Router};
+use storage::{
+    DirectoryStorage, FileInfo, HideDotfiles, MemoryStorage, Storage, StorageError, TarStorage,
 };
 
-use flate2::{write::GzEncoder, Compression};
+use flate2::{
+    write::{GzEncoder, ZlibEncoder},
+    Compression,
+};
 
 const CRLF: &str = "\r\n";
+const UNAVAILABLE_RETRY_AFTER_SECS: u64 = 30;
+const DEFAULT_MAX_BODY_SIZE: u64 = 10 * 1024 * 1024;
+const DEFAULT_MAX_REQUEST_LINE: usize = 8 * 1024;
+const DEFAULT_MAX_HEADER_LINE: usize = 8 * 1024;
+const DEFAULT_MAX_HEADER_BYTES: usize = 64 * 1024;
+const DEFAULT_MAX_HEADER_COUNT: usize = 100;
+/// One worker for `/poll` to wait in and one kept free for `/notify`.
+const MIN_THREADS: usize = 2;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum Method {
     Get,
     Head,
@@ -29,6 +66,22 @@ enum Method {
     Patch,
 }
 
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Method::Get => write!(f, "GET"),
+            Method::Head => write!(f, "HEAD"),
+            Method::Post => write!(f, "POST"),
+            Method::Put => write!(f, "PUT"),
+            Method::Delete => write!(f, "DELETE"),
+            Method::Connect => write!(f, "CONNECT"),
+            Method::Options => write!(f, "OPTIONS"),
+            Method::Trace => write!(f, "TRACE"),
+            Method::Patch => write!(f, "PATCH"),
+        }
+    }
+}
+
 impl FromStr for Method {
     type Err = ();
 
@@ -54,8 +107,11 @@ pub enum HeaderType {
     AcceptCharset,
     AcceptEncoding,
     AcceptLanguage,
+    AcceptRanges,
+    AccessControlExposeHeaders,
     AccessControlRequestMethod,
     AccessControlRequestHeaders,
+    Allow,
     Authorization,
     CacheControl,
     Connection,
@@ -63,9 +119,11 @@ pub enum HeaderType {
     ContentEncoding,
     ContentLanguage,
     ContentLength,
+    ContentRange,
     ContentType,
     Cookie,
     Date,
+    ETag,
     Expect,
     Forwarded,
     From,
@@ -75,6 +133,7 @@ pub enum HeaderType {
     IfNoneMatch,
     IfRange,
     IfUnmodifiedSince,
+    LastModified,
     MaxForwards,
     Origin,
     Pragma,
@@ -82,13 +141,17 @@ pub enum HeaderType {
     ProxyAuthorization,
     Range,
     Referer,
+    RetryAfter,
+    Server,
     TE,
     Trailer,
     TransferEncoding,
     UserAgent,
     Upgrade,
+    Vary,
     Via,
     Warning,
+    XContentTypeOptions,
     Custom(String),
 }
 
@@ -99,8 +162,11 @@ impl fmt::Display for HeaderType {
             HeaderType::AcceptCharset => write!(f, "Accept-Charset"),
             HeaderType::AcceptEncoding => write!(f, "Accept-Encoding"),
             HeaderType::AcceptLanguage => write!(f, "Accept-Language"),
+            HeaderType::AcceptRanges => write!(f, "Accept-Ranges"),
+            HeaderType::AccessControlExposeHeaders => write!(f, "Access-Control-Expose-Headers"),
             HeaderType::AccessControlRequestMethod => write!(f, "Access-Control-Request-Method"),
             HeaderType::AccessControlRequestHeaders => write!(f, "Access-Control-Request-Headers"),
+            HeaderType::Allow => write!(f, "Allow"),
             HeaderType::Authorization => write!(f, "Authorization"),
             HeaderType::CacheControl => write!(f, "Cache-Control"),
             HeaderType::Connection => write!(f, "Connection"),
@@ -108,9 +174,11 @@ impl fmt::Display for HeaderType {
             HeaderType::ContentEncoding => write!(f, "Content-Encoding"),
             HeaderType::ContentLanguage => write!(f, "Content-Language"),
             HeaderType::ContentLength => write!(f, "Content-Length"),
+            HeaderType::ContentRange => write!(f, "Content-Range"),
             HeaderType::ContentType => write!(f, "Content-Type"),
             HeaderType::Cookie => write!(f, "Cookie"),
             HeaderType::Date => write!(f, "Date"),
+            HeaderType::ETag => write!(f, "ETag"),
             HeaderType::Expect => write!(f, "Expect"),
             HeaderType::Forwarded => write!(f, "Forwarded"),
             HeaderType::From => write!(f, "From"),
@@ -120,6 +188,7 @@ impl fmt::Display for HeaderType {
             HeaderType::IfNoneMatch => write!(f, "If-None-Match"),
             HeaderType::IfRange => write!(f, "If-Range"),
             HeaderType::IfUnmodifiedSince => write!(f, "If-Unmodified-Since"),
+            HeaderType::LastModified => write!(f, "Last-Modified"),
             HeaderType::MaxForwards => write!(f, "Max-Forwards"),
             HeaderType::Origin => write!(f, "Origin"),
             HeaderType::Pragma => write!(f, "Pragma"),
@@ -127,13 +196,17 @@ impl fmt::Display for HeaderType {
             HeaderType::ProxyAuthorization => write!(f, "Proxy-Authorization"),
             HeaderType::Range => write!(f, "Range"),
             HeaderType::Referer => write!(f, "Referer"),
+            HeaderType::RetryAfter => write!(f, "Retry-After"),
+            HeaderType::Server => write!(f, "Server"),
             HeaderType::TE => write!(f, "TE"),
             HeaderType::Trailer => write!(f, "Trailer"),
             HeaderType::TransferEncoding => write!(f, "Transfer-Encoding"),
             HeaderType::UserAgent => write!(f, "User-Agent"),
             HeaderType::Upgrade => write!(f, "Upgrade"),
+            HeaderType::Vary => write!(f, "Vary"),
             HeaderType::Via => write!(f, "Via"),
             HeaderType::Warning => write!(f, "Warning"),
+            HeaderType::XContentTypeOptions => write!(f, "X-Content-Type-Options"),
             HeaderType::Custom(name) => write!(f, "{}", name),
         }
     }
@@ -142,53 +215,66 @@ impl fmt::Display for HeaderType {
 impl FromStr for HeaderType {
     type Err = ();
 
+    /// Field names are case-insensitive, so they are matched lowercased and
+    /// unknown names are kept lowercased, making `Custom` keys compare the
+    /// same way.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "Accept" => Ok(HeaderType::Accept),
-            "Accept-Charset" => Ok(HeaderType::AcceptCharset),
-            "Accept-Encoding" => Ok(HeaderType::AcceptEncoding),
-            "Accept-Language" => Ok(HeaderType::AcceptLanguage),
-            "Access-Control-Request-Method" => Ok(HeaderType::AccessControlRequestMethod),
-            "Access-Control-Request-Headers" => Ok(HeaderType::AccessControlRequestHeaders),
-            "Authorization" => Ok(HeaderType::Authorization),
-            "Cache-Control" => Ok(HeaderType::CacheControl),
-            "Connection" => Ok(HeaderType::Connection),
-            "Content-Disposition" => Ok(HeaderType::ContentDisposition),
-            "Content-Encoding" => Ok(HeaderType::ContentEncoding),
-            "Content-Language" => Ok(HeaderType::ContentLanguage),
-            "Content-Length" => Ok(HeaderType::ContentLength),
-            "Content-Type" => Ok(HeaderType::ContentType),
-            "Cookie" => Ok(HeaderType::Cookie),
-            "Date" => Ok(HeaderType::Date),
-            "Expect" => Ok(HeaderType::Expect),
-            "Forwarded" => Ok(HeaderType::Forwarded),
-            "From" => Ok(HeaderType::From),
-            "Host" => Ok(HeaderType::Host),
-            "If-Match" => Ok(HeaderType::IfMatch),
-            "If-Modified-Since" => Ok(HeaderType::IfModifiedSince),
-            "If-None-Match" => Ok(HeaderType::IfNoneMatch),
-            "If-Range" => Ok(HeaderType::IfRange),
-            "If-Unmodified-Since" => Ok(HeaderType::IfUnmodifiedSince),
-            "Max-Forwards" => Ok(HeaderType::MaxForwards),
-            "Origin" => Ok(HeaderType::Origin),
-            "Pragma" => Ok(HeaderType::Pragma),
-            "Proxy-Authenticate" => Ok(HeaderType::ProxyAuthenticate),
-            "Proxy-Authorization" => Ok(HeaderType::ProxyAuthorization),
-            "Range" => Ok(HeaderType::Range),
-            "Referer" => Ok(HeaderType::Referer),
-            "TE" => Ok(HeaderType::TE),
-            "Trailer" => Ok(HeaderType::Trailer),
-            "Transfer-Encoding" => Ok(HeaderType::TransferEncoding),
-            "User-Agent" => Ok(HeaderType::UserAgent),
-            "Upgrade" => Ok(HeaderType::Upgrade),
-            "Via" => Ok(HeaderType::Via),
-            "Warning" => Ok(HeaderType::Warning),
+        match s.to_ascii_lowercase().as_str() {
+            "accept" => Ok(HeaderType::Accept),
+            "accept-charset" => Ok(HeaderType::AcceptCharset),
+            "accept-encoding" => Ok(HeaderType::AcceptEncoding),
+            "accept-language" => Ok(HeaderType::AcceptLanguage),
+            "accept-ranges" => Ok(HeaderType::AcceptRanges),
+            "access-control-expose-headers" => Ok(HeaderType::AccessControlExposeHeaders),
+            "access-control-request-method" => Ok(HeaderType::AccessControlRequestMethod),
+            "access-control-request-headers" => Ok(HeaderType::AccessControlRequestHeaders),
+            "allow" => Ok(HeaderType::Allow),
+            "authorization" => Ok(HeaderType::Authorization),
+            "cache-control" => Ok(HeaderType::CacheControl),
+            "connection" => Ok(HeaderType::Connection),
+            "content-disposition" => Ok(HeaderType::ContentDisposition),
+            "content-encoding" => Ok(HeaderType::ContentEncoding),
+            "content-language" => Ok(HeaderType::ContentLanguage),
+            "content-length" => Ok(HeaderType::ContentLength),
+            "content-range" => Ok(HeaderType::ContentRange),
+            "content-type" => Ok(HeaderType::ContentType),
+            "cookie" => Ok(HeaderType::Cookie),
+            "date" => Ok(HeaderType::Date),
+            "etag" => Ok(HeaderType::ETag),
+            "expect" => Ok(HeaderType::Expect),
+            "forwarded" => Ok(HeaderType::Forwarded),
+            "from" => Ok(HeaderType::From),
+            "host" => Ok(HeaderType::Host),
+            "if-match" => Ok(HeaderType::IfMatch),
+            "if-modified-since" => Ok(HeaderType::IfModifiedSince),
+            "if-none-match" => Ok(HeaderType::IfNoneMatch),
+            "if-range" => Ok(HeaderType::IfRange),
+            "if-unmodified-since" => Ok(HeaderType::IfUnmodifiedSince),
+            "last-modified" => Ok(HeaderType::LastModified),
+            "max-forwards" => Ok(HeaderType::MaxForwards),
+            "origin" => Ok(HeaderType::Origin),
+            "pragma" => Ok(HeaderType::Pragma),
+            "proxy-authenticate" => Ok(HeaderType::ProxyAuthenticate),
+            "proxy-authorization" => Ok(HeaderType::ProxyAuthorization),
+            "range" => Ok(HeaderType::Range),
+            "referer" => Ok(HeaderType::Referer),
+            "retry-after" => Ok(HeaderType::RetryAfter),
+            "server" => Ok(HeaderType::Server),
+            "te" => Ok(HeaderType::TE),
+            "trailer" => Ok(HeaderType::Trailer),
+            "transfer-encoding" => Ok(HeaderType::TransferEncoding),
+            "user-agent" => Ok(HeaderType::UserAgent),
+            "upgrade" => Ok(HeaderType::Upgrade),
+            "vary" => Ok(HeaderType::Vary),
+            "via" => Ok(HeaderType::Via),
+            "warning" => Ok(HeaderType::Warning),
+            "x-content-type-options" => Ok(HeaderType::XContentTypeOptions),
             other => Ok(HeaderType::Custom(other.to_string())),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum EncodingType {
     Gzip,
     Compress,
@@ -224,18 +310,131 @@ impl FromStr for EncodingType {
     }
 }
 
+impl EncodingType {
+    fn encode(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            EncodingType::Gzip => {
+                let mut encoder = GzEncoder::new(vec![], Compression::default());
+                encoder.write_all(data)?;
+                Ok(encoder.finish()?)
+            }
+            // HTTP's "deflate" is the zlib format, not a raw deflate stream.
+            EncodingType::Deflate => {
+                let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+                encoder.write_all(data)?;
+                Ok(encoder.finish()?)
+            }
+            EncodingType::Compress | EncodingType::Brotli | EncodingType::Zstd => {
+                Err(format!("{} encoding is not supported", self).into())
+            }
+        }
+    }
+}
+
+/// Encodings we can actually produce, in order of preference on ties.
+const SUPPORTED_ENCODINGS: [EncodingType; 2] = [EncodingType::Gzip, EncodingType::Deflate];
+
+/// Splits an `Accept-Encoding` value into lowercased codings and their
+/// q-values. A coding without `q=` weighs 1, a malformed `q=` weighs 0.
+fn coding_weights(accept: &str) -> Vec<(String, f32)> {
+    let mut weights = Vec::new();
+    for item in accept.split(',') {
+        let mut params = item.split(';');
+        let coding = params.next().unwrap_or_default().trim().to_lowercase();
+        if coding.is_empty() {
+            continue;
+        }
+        let mut q = 1.0;
+        for param in params {
+            if let Some((name, value)) = param.split_once('=') {
+                if name.trim().eq_ignore_ascii_case("q") {
+                    q = value.trim().parse().unwrap_or(0.0);
+                }
+            }
+        }
+        weights.push((coding, q));
+    }
+    weights
+}
+
+/// Weight of `coding`, falling back to the `*` entry when it isn't listed.
+fn weight_of(weights: &[(String, f32)], coding: &str) -> Option<f32> {
+    weights
+        .iter()
+        .find(|(name, _)| name == coding)
+        .or_else(|| weights.iter().find(|(name, _)| name == "*"))
+        .map(|(_, q)| *q)
+}
+
+/// Picks the single best of the `enabled` encodings from an `Accept-Encoding`
+/// value, honoring q-values, `identity` and `*`. `None` means the body is
+/// sent as-is.
+fn select_encoding(accept: &str, enabled: &[EncodingType]) -> Option<EncodingType> {
+    let weights = coding_weights(accept);
+
+    let mut best: Option<(EncodingType, f32)> = None;
+    for &encoding in enabled {
+        let q = weight_of(&weights, &encoding.to_string()).unwrap_or(0.0);
+        if q > 0.0 && !best.is_some_and(|(_, best_q)| q <= best_q) {
+            best = Some((encoding, q));
+        }
+    }
+
+    let (encoding, q) = best?;
+    let identity_q = weights
+        .iter()
+        .find(|(name, _)| name == "identity")
+        .map_or(0.0, |(_, q)| *q);
+    (q >= identity_q).then_some(encoding)
+}
+
+/// Whether the client ruled out unencoded bodies, via `identity;q=0` or a
+/// `*;q=0` that doesn't list identity separately.
+fn identity_refused(accept: &str) -> bool {
+    weight_of(&coding_weights(accept), "identity") == Some(0.0)
+}
+
 impl HeaderType {
+    /// Fields that control framing, routing, authentication or request
+    /// handling must come before the body and are ignored in trailers.
+    fn allowed_in_trailer(&self) -> bool {
+        !matches!(
+            self,
+            HeaderType::Authorization
+                | HeaderType::CacheControl
+                | HeaderType::Connection
+                | HeaderType::ContentEncoding
+                | HeaderType::ContentLength
+                | HeaderType::ContentType
+                | HeaderType::Cookie
+                | HeaderType::Expect
+                | HeaderType::Host
+                | HeaderType::MaxForwards
+                | HeaderType::ProxyAuthorization
+                | HeaderType::Range
+                | HeaderType::TE
+                | HeaderType::Trailer
+                | HeaderType::TransferEncoding
+                | HeaderType::Upgrade
+        )
+    }
+
+    /// Splits a `Name: value` line. Whitespace inside or around the name,
+    /// including the obsolete line folding, makes the line invalid, since
+    /// other parsers may read such a field differently.
     fn parse(line: &str) -> Option<(Self, String)> {
-        let mut parts = line.splitn(2, ':');
-        let key = parts.next()?.trim();
-        let value = parts.next()?.trim().to_string();
+        let (key, value) = line.split_once(':')?;
+        if key.is_empty() || key.contains(|c: char| c.is_ascii_whitespace()) {
+            return None;
+        }
+        let value = value.trim().to_string();
         let header_type = Self::from_str(key).ok()?;
 
         Some((header_type, value))
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum StatusCode {
     Continue = 100,
     SwitchingProtocols = 101,
@@ -304,141 +503,627 @@ pub enum StatusCode {
     NetworkAuthenticationRequired = 511,
 }
 
+/// The first digit of a status code, per RFC 9110 section 15.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusClass {
+    Informational,
+    Success,
+    Redirection,
+    ClientError,
+    ServerError,
+}
+
+impl StatusCode {
+    pub fn code(&self) -> u16 {
+        *self as u16
+    }
+
+    /// The variant for a numeric code, if it is one we know.
+    pub fn from_code(code: u16) -> Option<Self> {
+        Some(match code {
+            100 => StatusCode::Continue,
+            101 => StatusCode::SwitchingProtocols,
+            102 => StatusCode::Processing,
+            200 => StatusCode::Ok,
+            201 => StatusCode::Created,
+            202 => StatusCode::Accepted,
+            203 => StatusCode::NonAuthoritativeInfo,
+            204 => StatusCode::NoContent,
+            205 => StatusCode::ResetContent,
+            206 => StatusCode::PartialContent,
+            207 => StatusCode::MultiStatus,
+            208 => StatusCode::AlreadyReported,
+            226 => StatusCode::ImUsed,
+            300 => StatusCode::MultipleChoices,
+            301 => StatusCode::MovedPermanently,
+            302 => StatusCode::Found,
+            303 => StatusCode::SeeOther,
+            304 => StatusCode::NotModified,
+            305 => StatusCode::UseProxy,
+            307 => StatusCode::TemporaryRedirect,
+            308 => StatusCode::PermanentRedirect,
+            400 => StatusCode::BadRequest,
+            401 => StatusCode::Unauthorized,
+            402 => StatusCode::PaymentRequired,
+            403 => StatusCode::Forbidden,
+            404 => StatusCode::NotFound,
+            405 => StatusCode::MethodNotAllowed,
+            406 => StatusCode::NotAcceptable,
+            407 => StatusCode::ProxyAuthRequired,
+            408 => StatusCode::RequestTimeout,
+            409 => StatusCode::Conflict,
+            410 => StatusCode::Gone,
+            411 => StatusCode::LengthRequired,
+            412 => StatusCode::PreconditionFailed,
+            413 => StatusCode::PayloadTooLarge,
+            414 => StatusCode::UriTooLong,
+            415 => StatusCode::UnsupportedMediaType,
+            416 => StatusCode::RangeNotSatisfiable,
+            417 => StatusCode::ExpectationFailed,
+            418 => StatusCode::ImATeapot,
+            421 => StatusCode::MisdirectedRequest,
+            422 => StatusCode::UnprocessableEntity,
+            423 => StatusCode::Locked,
+            424 => StatusCode::FailedDependency,
+            425 => StatusCode::TooEarly,
+            426 => StatusCode::UpgradeRequired,
+            428 => StatusCode::PreconditionRequired,
+            429 => StatusCode::TooManyRequests,
+            431 => StatusCode::RequestHeaderFieldsTooLarge,
+            451 => StatusCode::UnavailableForLegalReasons,
+            500 => StatusCode::InternalServerError,
+            501 => StatusCode::NotImplemented,
+            502 => StatusCode::BadGateway,
+            503 => StatusCode::ServiceUnavailable,
+            504 => StatusCode::GatewayTimeout,
+            505 => StatusCode::HttpVersionNotSupported,
+            506 => StatusCode::VariantAlsoNegotiates,
+            507 => StatusCode::InsufficientStorage,
+            508 => StatusCode::LoopDetected,
+            510 => StatusCode::NotExtended,
+            511 => StatusCode::NetworkAuthenticationRequired,
+            _ => return None,
+        })
+    }
+
+    /// The registered reason phrase, e.g. `"OK"` for 200.
+    pub fn reason_phrase(&self) -> &'static str {
+        match self {
+            StatusCode::Continue => "Continue",
+            StatusCode::SwitchingProtocols => "Switching Protocols",
+            StatusCode::Processing => "Processing",
+            StatusCode::Ok => "OK",
+            StatusCode::Created => "Created",
+            StatusCode::Accepted => "Accepted",
+            StatusCode::NonAuthoritativeInfo => "Non-Authoritative Information",
+            StatusCode::NoContent => "No Content",
+            StatusCode::ResetContent => "Reset Content",
+            StatusCode::PartialContent => "Partial Content",
+            StatusCode::MultiStatus => "Multi-Status",
+            StatusCode::AlreadyReported => "Already Reported",
+            StatusCode::ImUsed => "IM Used",
+            StatusCode::MultipleChoices => "Multiple Choices",
+            StatusCode::MovedPermanently => "Moved Permanently",
+            StatusCode::Found => "Found",
+            StatusCode::SeeOther => "See Other",
+            StatusCode::NotModified => "Not Modified",
+            StatusCode::UseProxy => "Use Proxy",
+            StatusCode::TemporaryRedirect => "Temporary Redirect",
+            StatusCode::PermanentRedirect => "Permanent Redirect",
+            StatusCode::BadRequest => "Bad Request",
+            StatusCode::Unauthorized => "Unauthorized",
+            StatusCode::PaymentRequired => "Payment Required",
+            StatusCode::Forbidden => "Forbidden",
+            StatusCode::NotFound => "Not Found",
+            StatusCode::MethodNotAllowed => "Method Not Allowed",
+            StatusCode::NotAcceptable => "Not Acceptable",
+            StatusCode::ProxyAuthRequired => "Proxy Authentication Required",
+            StatusCode::RequestTimeout => "Request Timeout",
+            StatusCode::Conflict => "Conflict",
+            StatusCode::Gone => "Gone",
+            StatusCode::LengthRequired => "Length Required",
+            StatusCode::PreconditionFailed => "Precondition Failed",
+            StatusCode::PayloadTooLarge => "Payload Too Large",
+            StatusCode::UriTooLong => "URI Too Long",
+            StatusCode::UnsupportedMediaType => "Unsupported Media Type",
+            StatusCode::RangeNotSatisfiable => "Range Not Satisfiable",
+            StatusCode::ExpectationFailed => "Expectation Failed",
+            StatusCode::ImATeapot => "I'm a teapot",
+            StatusCode::MisdirectedRequest => "Misdirected Request",
+            StatusCode::UnprocessableEntity => "Unprocessable Entity",
+            StatusCode::Locked => "Locked",
+            StatusCode::FailedDependency => "Failed Dependency",
+            StatusCode::TooEarly => "Too Early",
+            StatusCode::UpgradeRequired => "Upgrade Required",
+            StatusCode::PreconditionRequired => "Precondition Required",
+            StatusCode::TooManyRequests => "Too Many Requests",
+            StatusCode::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+            StatusCode::UnavailableForLegalReasons => "Unavailable For Legal Reasons",
+            StatusCode::InternalServerError => "Internal Server Error",
+            StatusCode::NotImplemented => "Not Implemented",
+            StatusCode::BadGateway => "Bad Gateway",
+            StatusCode::ServiceUnavailable => "Service Unavailable",
+            StatusCode::GatewayTimeout => "Gateway Timeout",
+            StatusCode::HttpVersionNotSupported => "HTTP Version Not Supported",
+            StatusCode::VariantAlsoNegotiates => "Variant Also Negotiates",
+            StatusCode::InsufficientStorage => "Insufficient Storage",
+            StatusCode::LoopDetected => "Loop Detected",
+            StatusCode::NotExtended => "Not Extended",
+            StatusCode::NetworkAuthenticationRequired => "Network Authentication Required",
+        }
+    }
+
+    pub fn class(&self) -> StatusClass {
+        match self.code() {
+            100..=199 => StatusClass::Informational,
+            200..=299 => StatusClass::Success,
+            300..=399 => StatusClass::Redirection,
+            400..=499 => StatusClass::ClientError,
+            _ => StatusClass::ServerError,
+        }
+    }
+}
+
 impl fmt::Display for StatusCode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self {
-                StatusCode::Continue => "100 Continue",
-                StatusCode::SwitchingProtocols => "101 Switching Protocols",
-                StatusCode::Processing => "102 Processing",
-                StatusCode::Ok => "200 OK",
-                StatusCode::Created => "201 Created",
-                StatusCode::Accepted => "202 Accepted",
-                StatusCode::NonAuthoritativeInfo => "203 Non-Authoritative Information",
-                StatusCode::NoContent => "204 No Content",
-                StatusCode::ResetContent => "205 Reset Content",
-                StatusCode::PartialContent => "206 Partial Content",
-                StatusCode::MultiStatus => "207 Multi-Status",
-                StatusCode::AlreadyReported => "208 Already Reported",
-                StatusCode::ImUsed => "226 IM Used",
-                StatusCode::MultipleChoices => "300 Multiple Choices",
-                StatusCode::MovedPermanently => "301 Moved Permanently",
-                StatusCode::Found => "302 Found",
-                StatusCode::SeeOther => "303 See Other",
-                StatusCode::NotModified => "304 Not Modified",
-                StatusCode::UseProxy => "305 Use Proxy",
-                StatusCode::TemporaryRedirect => "307 Temporary Redirect",
-                StatusCode::PermanentRedirect => "308 Permanent Redirect",
-                StatusCode::BadRequest => "400 Bad Request",
-                StatusCode::Unauthorized => "401 Unauthorized",
-                StatusCode::PaymentRequired => "402 Payment Required",
-                StatusCode::Forbidden => "403 Forbidden",
-                StatusCode::NotFound => "404 Not Found",
-                StatusCode::MethodNotAllowed => "405 Method Not Allowed",
-                StatusCode::NotAcceptable => "406 Not Acceptable",
-                StatusCode::ProxyAuthRequired => "407 Proxy Authentication Required",
-                StatusCode::RequestTimeout => "408 Request Timeout",
-                StatusCode::Conflict => "409 Conflict",
-                StatusCode::Gone => "410 Gone",
-                StatusCode::LengthRequired => "411 Length Required",
-                StatusCode::PreconditionFailed => "412 Precondition Failed",
-                StatusCode::PayloadTooLarge => "413 Payload Too Large",
-                StatusCode::UriTooLong => "414 URI Too Long",
-                StatusCode::UnsupportedMediaType => "415 Unsupported Media Type",
-                StatusCode::RangeNotSatisfiable => "416 Range Not Satisfiable",
-                StatusCode::ExpectationFailed => "417 Expectation Failed",
-                StatusCode::ImATeapot => "418 I'm a teapot",
-                StatusCode::MisdirectedRequest => "421 Misdirected Request",
-                StatusCode::UnprocessableEntity => "422 Unprocessable Entity",
-                StatusCode::Locked => "423 Locked",
-                StatusCode::FailedDependency => "424 Failed Dependency",
-                StatusCode::TooEarly => "425 Too Early",
-                StatusCode::UpgradeRequired => "426 Upgrade Required",
-                StatusCode::PreconditionRequired => "428 Precondition Required",
-                StatusCode::TooManyRequests => "429 Too Many Requests",
-                StatusCode::RequestHeaderFieldsTooLarge => "431 Request Header Fields Too Large",
-                StatusCode::UnavailableForLegalReasons => "451 Unavailable For Legal Reasons",
-                StatusCode::InternalServerError => "500 Internal Server Error",
-                StatusCode::NotImplemented => "501 Not Implemented",
-                StatusCode::BadGateway => "502 Bad Gateway",
-                StatusCode::ServiceUnavailable => "503 Service Unavailable",
-                StatusCode::GatewayTimeout => "504 Gateway Timeout",
-                StatusCode::HttpVersionNotSupported => "505 HTTP Version Not Supported",
-                StatusCode::VariantAlsoNegotiates => "506 Variant Also Negotiates",
-                StatusCode::InsufficientStorage => "507 Insufficient Storage",
-                StatusCode::LoopDetected => "508 Loop Detected",
-                StatusCode::NotExtended => "510 Not Extended",
-                StatusCode::NetworkAuthenticationRequired => "511 Network Authentication Required",
+        write!(f, "{} {}", self.code(), self.reason_phrase())
+    }
+}
+
+/// The four request-target forms from RFC 7230 section 5.3.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TargetForm {
+    /// `/path?query`, used for ordinary requests.
+    Origin,
+    /// `http://host/path?query`, sent to proxies.
+    Absolute,
+    /// `host:port`, only used with CONNECT.
+    Authority,
+    /// `*`, only used with server-wide OPTIONS.
+    Asterisk,
+}
+
+#[derive(Debug)]
+struct RequestTarget {
+    form: TargetForm,
+    /// The target exactly as it appeared on the request line.
+    raw: String,
+    authority: Option<String>,
+    /// Percent-decoded path, empty for authority and asterisk forms. An
+    /// encoded `/` reads as a separator here, so it is only for display.
+    path: String,
+    /// The path split at its literal `/`s, then each segment decoded on its
+    /// own; routing matches on these. An encoded `/` stays inside its
+    /// segment as data.
+    segments: Vec<String>,
+    /// Percent-decoded query parameters. A repeated key keeps its last
+    /// value, and a key without `=` maps to an empty string.
+    query: HashMap<String, String>,
+}
+
+impl FromStr for RequestTarget {
+    type Err = ParseError;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseError::InvalidTarget(raw.to_owned());
+
+        let (form, authority, path_and_query) = if raw == "*" {
+            (TargetForm::Asterisk, None, "")
+        } else if raw.starts_with('/') {
+            (TargetForm::Origin, None, raw)
+        } else if let Some((_, rest)) = raw.split_once("://") {
+            let (authority, path_and_query) = match rest.find(['/', '?']) {
+                Some(index) => rest.split_at(index),
+                None => (rest, "/"),
+            };
+            (TargetForm::Absolute, Some(authority), path_and_query)
+        } else {
+            (TargetForm::Authority, Some(raw), "")
+        };
+        if authority.is_some_and(str::is_empty) {
+            return Err(invalid());
+        }
+
+        let (path, query) = path_and_query
+            .split_once('?')
+            .unwrap_or((path_and_query, ""));
+        // Split before decoding, so `%2F` can't add a segment.
+        let segments = match path.strip_prefix('/') {
+            Some(path) => path
+                .split('/')
+                .map(|segment| percent_decode(segment, false))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(invalid)?,
+            // `http://host` and `http://host?query` mean the root.
+            None if form == TargetForm::Absolute => vec![String::new()],
+            None => Vec::new(),
+        };
+        let path = if segments.is_empty() {
+            String::new()
+        } else {
+            format!("/{}", segments.join("/"))
+        };
+        let mut params = HashMap::new();
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            params.insert(
+                percent_decode(key, true).ok_or_else(invalid)?,
+                percent_decode(value, true).ok_or_else(invalid)?,
+            );
+        }
+
+        Ok(Self {
+            form,
+            raw: raw.to_owned(),
+            authority: authority.map(str::to_owned),
+            path,
+            segments,
+            query: params,
+        })
+    }
+}
+
+/// Decodes `%XX` escapes, and `+` as a space when `plus_as_space` is set
+/// (query strings). Returns `None` for malformed escapes, an encoded NUL or
+/// invalid UTF-8.
+fn percent_decode(input: &str, plus_as_space: bool) -> Option<String> {
+    let mut bytes = input.bytes();
+    let mut decoded = Vec::with_capacity(input.len());
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                let hex = std::str::from_utf8(&hex).ok()?;
+                match u8::from_str_radix(hex, 16).ok()? {
+                    0 => return None,
+                    byte => decoded.push(byte),
+                }
             }
-        )
+            b'+' if plus_as_space => decoded.push(b' '),
+            _ => decoded.push(byte),
+        }
     }
+    String::from_utf8(decoded).ok()
 }
 
 #[derive(Debug)]
 struct HttpRequest {
     method: Method,
-    path: String,
+    target: RequestTarget,
     version: String,
     headers: HashMap<HeaderType, String>,
     body: Vec<u8>,
+    /// Values captured from the matched route pattern, filled in by [`Router`].
+    params: HashMap<String, String>,
+    /// The segments a trailing `*name` matched, still apart. Its value in
+    /// `params` joins them with `/`, which hides an encoded one inside.
+    rest: Vec<String>,
 }
 
-impl From<&TcpStream> for HttpRequest {
-    fn from(connection: &TcpStream) -> Self {
-        let mut reader = BufReader::new(connection);
-
-        let mut request_line = String::new();
-        reader.read_line(&mut request_line).unwrap();
-        let parts: Vec<_> = request_line.split_whitespace().collect();
-        let method = parts[0].parse().unwrap();
-        let path = parts[1].to_string();
-        let version = parts[2].to_string();
+impl HttpRequest {
+    fn parse<R: BufRead>(reader: &mut R) -> Result<Self, ParseError> {
+        let limits = Limits::default();
+        let mut request = Self::parse_head(reader, &limits)?;
+        request.body = read_body(reader, &mut request.headers, false, &limits)?;
+        Ok(request)
+    }
 
-        let mut headers = HashMap::new();
-        loop {
-            let mut line = String::new();
-            reader.read_line(&mut line).unwrap();
-            if line.trim().is_empty() {
-                break;
-            }
-            if line == CRLF {
-                reader.read_line(&mut line).unwrap();
-                break;
-            }
-            if let Some((header_type, value)) = HeaderType::parse(&line) {
-                headers.insert(header_type, value);
-            }
+    /// Reads the request line and headers, leaving the body unread.
+    fn parse_head<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Self, ParseError> {
+        let request_line = read_line_limited(reader, limits.request_line, || {
+            ParseError::RequestLineTooLong
+        })?;
+        if request_line.is_empty() {
+            return Err(ParseError::ConnectionClosed);
+        }
+        reject_nul(&request_line)?;
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().ok_or(ParseError::MissingMethod)?;
+        let method: Method = method
+            .parse()
+            .map_err(|_| ParseError::UnknownMethod(method.to_owned()))?;
+        let target: RequestTarget = parts.next().ok_or(ParseError::MissingPath)?.parse()?;
+        // Authority form belongs to CONNECT and `*` to OPTIONS; anywhere
+        // else they'd reach the router as an empty path.
+        let form_allowed = match target.form {
+            TargetForm::Authority => method == Method::Connect,
+            TargetForm::Asterisk => method == Method::Options,
+            TargetForm::Origin | TargetForm::Absolute => method != Method::Connect,
+        };
+        if !form_allowed {
+            return Err(ParseError::InvalidTarget(target.raw));
+        }
+        let version = parts.next().ok_or(ParseError::MissingVersion)?.to_string();
+        if parts.next().is_some() {
+            return Err(ParseError::MalformedRequestLine);
         }
 
-        let mut body = Vec::new();
-        if let Some(content_length_str) = headers.get(&HeaderType::ContentLength) {
-            let content_length: usize = content_length_str.parse().unwrap();
-            body.resize(content_length, 0);
-            reader.read_exact(&mut body).unwrap();
+        let headers = read_headers(reader, limits)?;
+        // HTTP/1.0 has no transfer codings, so an intermediary may frame
+        // the body differently from us (RFC 9112 section 6.1).
+        if version == "HTTP/1.0" && headers.contains_key(&HeaderType::TransferEncoding) {
+            return Err(ParseError::TransferEncodingInHttp10);
         }
 
-        Self {
+        Ok(Self {
             method,
-            path,
+            target,
             version,
             headers,
-            body,
+            body: Vec::new(),
+            params: HashMap::new(),
+            rest: Vec::new(),
+        })
+    }
+}
+
+impl HttpRequest {
+    /// Looks up a decoded query parameter; see [`RequestTarget::query`] for
+    /// how repeated and valueless keys are handled.
+    fn query_param(&self, key: &str) -> Option<&str> {
+        self.target.query.get(key).map(String::as_str)
+    }
+
+    /// Looks up a `:name` or `*name` segment captured by the matched route.
+    fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(String::as_str)
+    }
+
+    /// Whether the client wants the connection kept open after this request.
+    /// HTTP/1.1 connections persist unless closed, HTTP/1.0 ones must opt in.
+    fn keep_alive(&self) -> bool {
+        let has_token = |token: &str| {
+            self.headers
+                .get(&HeaderType::Connection)
+                .is_some_and(|value| {
+                    value
+                        .split(',')
+                        .any(|item| item.trim().eq_ignore_ascii_case(token))
+                })
+        };
+
+        if self.version == "HTTP/1.0" {
+            has_token("keep-alive")
+        } else {
+            !has_token("close")
+        }
+    }
+}
+
+impl HttpRequest {
+    /// Parses a request held entirely in memory. Bytes after the request,
+    /// such as a pipelined follow-up, are ignored.
+    fn from_bytes(data: &[u8]) -> Result<Self, ParseError> {
+        Self::parse(&mut Cursor::new(data))
+    }
+
+    /// Serializes the request back into HTTP/1.1 wire format. The body is
+    /// held decoded, so it is always framed with `Content-Length` and any
+    /// `Transfer-Encoding` the client sent is left out.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut head = format!("{} {} {}{CRLF}", self.method, self.target.raw, self.version);
+        for (key, value) in &self.headers {
+            if matches!(
+                key,
+                HeaderType::ContentLength | HeaderType::TransferEncoding
+            ) {
+                continue;
+            }
+            head.push_str(&format!("{}: {}{CRLF}", key, value));
+        }
+        if !self.body.is_empty() || self.headers.contains_key(&HeaderType::ContentLength) {
+            head.push_str(&format!(
+                "{}: {}{CRLF}",
+                HeaderType::ContentLength,
+                self.body.len()
+            ));
+        }
+        head.push_str(CRLF);
+
+        let mut bytes = head.into_bytes();
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+}
+
+impl TryFrom<&TcpStream> for HttpRequest {
+    type Error = ParseError;
+
+    fn try_from(connection: &TcpStream) -> Result<Self, Self::Error> {
+        Self::parse(&mut BufReader::new(connection))
+    }
+}
+
+/// A response body: held in memory, streamed from a reader whose length
+/// is known up front, or streamed with chunked framing until the reader
+/// runs dry.
+enum Body {
+    Bytes(Vec<u8>),
+    Reader {
+        reader: Box<dyn Read + Send>,
+        len: u64,
+    },
+    Chunked(Box<dyn Read + Send>),
+}
+
+impl Body {
+    /// The length to advertise in `Content-Length`, if known.
+    fn len(&self) -> Option<u64> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::Reader { len, .. } => Some(*len),
+            Body::Chunked(_) => None,
+        }
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Body::Bytes(bytes) => f.debug_tuple("Bytes").field(bytes).finish(),
+            Body::Reader { len, .. } => f.debug_struct("Reader").field("len", len).finish(),
+            Body::Chunked(_) => f.write_str("Chunked"),
         }
     }
 }
 
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Self {
+        Body::Bytes(bytes)
+    }
+}
+
+impl From<String> for Body {
+    fn from(text: String) -> Self {
+        Body::Bytes(text.into_bytes())
+    }
+}
+
 #[derive(Debug)]
 struct HttpResponse {
     version: String,
     status_code: StatusCode,
     headers: HashMap<HeaderType, String>,
-    body: Vec<u8>,
+    body: Body,
 }
 
 impl HttpResponse {
-    fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+    fn new(status_code: StatusCode) -> Self {
+        Self {
+            version: "HTTP/1.1".to_owned(),
+            status_code,
+            headers: HashMap::new(),
+            body: Vec::new().into(),
+        }
+    }
+
+    /// Parses a response held entirely in memory. A body without
+    /// `Content-Length` or chunked framing runs to the end of `data`, except
+    /// for statuses that never carry one.
+    ///
+    /// Failures are a [`ParseError`] rather than an [`HttpError`]: the
+    /// latter is what a handler answers a client with, while a malformed
+    /// response needs the parse detail, and the caller decides what status,
+    /// if any, to answer with.
+    fn from_bytes(data: &[u8]) -> Result<Self, ParseError> {
+        let limits = Limits::default();
+        let mut reader = Cursor::new(data);
+        let status_line = read_line_limited(&mut reader, limits.request_line, || {
+            ParseError::StatusLineTooLong
+        })?;
+        if status_line.is_empty() {
+            return Err(ParseError::UnexpectedEof);
+        }
+        reject_nul(&status_line)?;
+        let (version, status_code) = parse_status_line(&status_line)?;
+        let mut headers = read_headers(&mut reader, &limits)?;
+
+        let bodiless = matches!(status_code.class(), StatusClass::Informational)
+            || matches!(status_code, StatusCode::NoContent | StatusCode::NotModified);
+        let body = if bodiless {
+            Vec::new()
+        } else {
+            read_body(&mut reader, &mut headers, true, &limits)?
+        };
+
+        Ok(Self {
+            version,
+            status_code,
+            headers,
+            body: body.into(),
+        })
+    }
+
+    /// Adds a token to a comma-separated list header such as `Vary` or
+    /// `Connection`. Tokens already there, compared case-insensitively, are
+    /// not repeated and keep their order. In `Connection`, `close` displaces
+    /// `keep-alive`; in `Vary`, `*` already covers everything.
+    fn append_list_value(&mut self, header: HeaderType, token: &str) {
+        let mut tokens: Vec<&str> = self
+            .headers
+            .get(&header)
+            .map(|value| {
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|token| !token.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let present = |tokens: &[&str], wanted: &str| {
+            tokens
+                .iter()
+                .any(|token| token.eq_ignore_ascii_case(wanted))
+        };
+
+        match header {
+            HeaderType::Connection if token.eq_ignore_ascii_case("close") => {
+                tokens.retain(|token| !token.eq_ignore_ascii_case("keep-alive"));
+            }
+            HeaderType::Connection
+                if token.eq_ignore_ascii_case("keep-alive") && present(&tokens, "close") =>
+            {
+                return;
+            }
+            HeaderType::Vary if present(&tokens, "*") => return,
+            _ => {}
+        }
+        if !present(&tokens, token) {
+            tokens.push(token);
+        }
+        let value = tokens.join(", ");
+        self.headers.insert(header, value);
+    }
+
+    fn set_encoded_body(
+        &mut self,
+        encoding: Option<EncodingType>,
+        body: Vec<u8>,
+    ) -> Result<(), Error> {
+        self.append_list_value(HeaderType::Vary, &HeaderType::AcceptEncoding.to_string());
+        match encoding {
+            Some(encoding) => {
+                self.body = encoding.encode(&body)?.into();
+                self.headers
+                    .insert(HeaderType::ContentEncoding, encoding.to_string());
+            }
+            None => self.body = body.into(),
+        }
+        Ok(())
+    }
+
+    /// Catches a 206 whose `Content-Range` disagrees with the body it is
+    /// about to send, which would desync the client.
+    fn check_content_range(&self) -> Result<(), Error> {
+        if self.status_code != StatusCode::PartialContent {
+            return Ok(());
+        }
+        let range = self
+            .headers
+            .get(&HeaderType::ContentRange)
+            .ok_or("206 response without Content-Range")?;
+        let (start, end) = range
+            .strip_prefix("bytes ")
+            .and_then(|range| range.split_once('/'))
+            .and_then(|(span, _)| span.split_once('-'))
+            .and_then(|(start, end)| Some((start.parse::<u64>().ok()?, end.parse::<u64>().ok()?)))
+            .ok_or_else(|| format!("malformed Content-Range: {}", range))?;
+        let len = self
+            .body
+            .len()
+            .ok_or("206 response with a body of unknown length")?;
+        if end < start || end - start + 1 != len {
+            return Err(
+                format!("Content-Range {} does not match a {} byte body", range, len).into(),
+            );
+        }
+        Ok(())
+    }
+
+    fn write_head<W: Write>(&mut self, writer: &mut W) -> Result<(), Error> {
+        self.headers
+            .entry(HeaderType::Date)
+            .or_insert_with(|| format_http_date(SystemTime::now()));
         write!(writer, "{} {}{CRLF}", self.version, self.status_code)?;
 
         for (key, value) in &self.headers {
@@ -446,146 +1131,3072 @@ impl HttpResponse {
         }
 
         write!(writer, "{CRLF}")?;
-        writer.write_all(&self.body)?;
-        writer.flush()?;
-
         Ok(())
     }
-}
 
-fn connection_handler(mut conn: TcpStream, dir: Arc<String>) -> Result<(), Error> {
-    let request = HttpRequest::from(&conn);
-    let mut response = HttpResponse {
-        version: request.version,
-        status_code: StatusCode::Ok,
-        headers: HashMap::new(),
-        body: String::new().into(),
-    };
-
-    if let Some(values) = request.headers.get(&HeaderType::AcceptEncoding) {
-        let values: Vec<&str> = if values.contains(", ") {
-            values.split(", ").collect()
-        } else {
-            vec![values]
-        };
-        for value in values {
-            if let Ok(encoding_type) = EncodingType::from_str(value) {
-                if let Some(encoding_types) = response.headers.get_mut(&HeaderType::ContentEncoding)
-                {
-                    let new_encoding_types = format!("{}, {}", encoding_types, encoding_type);
-                    response
-                        .headers
-                        .insert(HeaderType::ContentEncoding, new_encoding_types);
-                } else {
-                    response
-                        .headers
-                        .insert(HeaderType::ContentEncoding, encoding_type.to_string());
+    fn write_to<W: Write>(&mut self, writer: &mut W) -> Result<(), Error> {
+        if matches!(self.body, Body::Chunked(_)) && self.version != "HTTP/1.0" {
+            return self.write_chunked_to(writer);
+        }
+        self.write_head(writer)?;
+        match &mut self.body {
+            Body::Bytes(bytes) => writer.write_all(bytes)?,
+            Body::Reader { reader, len } => {
+                let copied = io::copy(&mut reader.take(*len), writer)?;
+                if copied != *len {
+                    return Err(format!("body ended after {} of {} bytes", copied, len).into());
                 }
             }
+            // HTTP/1.0 has no chunked framing: the body runs until the
+            // connection closes.
+            Body::Chunked(reader) => {
+                io::copy(reader, writer)?;
+            }
         }
+        writer.flush()?;
+
+        Ok(())
     }
 
-    if request.path.starts_with("/echo") {
-        let parts: Vec<_> = request.path.split(|s| s == '/').collect();
-        let str = parts[2].to_string();
-        response
-            .headers
-            .insert(HeaderType::ContentType, "text/plain".to_owned());
+    /// Sends the body with `Transfer-Encoding: chunked` framing, one chunk
+    /// per read from the body, so its length never has to be known.
+    fn write_chunked_to<W: Write>(&mut self, writer: &mut W) -> Result<(), Error> {
+        self.headers.remove(&HeaderType::ContentLength);
+        self.headers
+            .insert(HeaderType::TransferEncoding, "chunked".to_owned());
+        self.write_head(writer)?;
 
-        if let Some(value) = response.headers.get(&HeaderType::ContentEncoding) {
-            if value == "gzip" {
-                let mut encoder = GzEncoder::new(vec![], Compression::default());
-                encoder.write_all(str.as_bytes())?;
-                response.body = encoder.finish()?;
-            } else {
-                response.body = str.into();
-            }
-        } else {
-            response.body = str.into();
-        }
-    } else if request.path.starts_with("/files") {
-        let parts: Vec<_> = request.path.split(|s| s == '/').collect();
-        let file_name = parts[2].to_string();
-        let file_path = format!("{}/{}", dir, file_name);
-
-        match request.method {
-            Method::Get => match read(file_path) {
-                Ok(file) => {
-                    response.headers.insert(
-                        HeaderType::ContentType,
-                        "application/octet-stream".to_owned(),
-                    );
-                    response.body = file;
-                }
-                Err(_) => response.status_code = StatusCode::NotFound,
-            },
-            Method::Post => match write(file_path, request.body) {
-                Ok(_) => response.status_code = StatusCode::Created,
-                Err(err) => {
-                    response.status_code = StatusCode::InternalServerError;
-                    response.body = err.to_string().as_bytes().to_vec();
-                }
-            },
-            _ => response.status_code = StatusCode::MethodNotAllowed,
+        let mut reader: Box<dyn Read + Send> =
+            match std::mem::replace(&mut self.body, Body::Bytes(Vec::new())) {
+                Body::Bytes(bytes) => Box::new(Cursor::new(bytes)),
+                Body::Reader { reader, len } => Box::new(reader.take(len)),
+                Body::Chunked(reader) => reader,
+            };
+        let mut buf = [0; 8192];
+        loop {
+            let read = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            };
+            write!(writer, "{:x}{CRLF}", read)?;
+            writer.write_all(&buf[..read])?;
+            write!(writer, "{CRLF}")?;
         }
-    } else if request.path == "/user-agent" {
-        let user_agent = request
-            .headers
-            .get(&HeaderType::UserAgent)
-            .unwrap()
-            .to_string();
-        response
-            .headers
-            .insert(HeaderType::ContentType, "text/plain".to_owned());
+        write!(writer, "0{CRLF}{CRLF}")?;
+        writer.flush()?;
 
-        response.body = user_agent.into();
-    } else if request.path == "/" {
-    } else {
-        response.status_code = StatusCode::NotFound;
+        Ok(())
     }
+}
 
-    response
-        .headers
-        .insert(HeaderType::ContentLength, response.body.len().to_string());
+#[derive(Debug, Clone)]
+enum Backend {
+    Directory(PathBuf),
+    Memory,
+    /// A tar archive, always read-only.
+    Tar(PathBuf),
+}
 
-    response.write_to(&mut conn)
+/// A file route: `GET <prefix>/<name>` reads from the backend, and writes
+/// and deletes are allowed when `writable`.
+#[derive(Debug, Clone)]
+struct Mount {
+    prefix: String,
+    backend: Backend,
+    writable: bool,
 }
 
-fn main() -> Result<(), Error> {
-    let mut directory = String::new();
-    let mut args = env::args().skip(1);
-    while let Some(arg) = args.next() {
-        if arg == "--directory" {
-            if let Some(dir) = args.next() {
-                directory = dir;
+impl FromStr for Mount {
+    type Err = Error;
+
+    /// Parses `<prefix>=<backend>[,rw]`, where the backend is `memory:`,
+    /// `tar:<archive>` or a directory path, optionally written `fs:<path>`.
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (prefix, rest) = spec
+            .split_once('=')
+            .ok_or_else(|| format!("invalid mount {}: expected <prefix>=<backend>", spec))?;
+        if !prefix.starts_with('/') || prefix.len() < 2 {
+            return Err(format!("invalid mount prefix: {}", prefix).into());
+        }
+        let (backend, writable) = match rest.strip_suffix(",rw") {
+            Some(backend) => (backend, true),
+            None => (rest.strip_suffix(",ro").unwrap_or(rest), false),
+        };
+        let backend = if backend == "memory:" {
+            Backend::Memory
+        } else if let Some(archive) = backend.strip_prefix("tar:") {
+            if archive.is_empty() {
+                return Err(format!("invalid mount {}: missing archive", spec).into());
+            }
+            if writable {
+                return Err(format!("invalid mount {}: tar mounts are read-only", spec).into());
+            }
+            Backend::Tar(PathBuf::from(archive))
+        } else {
+            let path = backend.strip_prefix("fs:").unwrap_or(backend);
+            if path.is_empty() {
+                return Err(format!("invalid mount {}: missing directory", spec).into());
             }
+            Backend::Directory(PathBuf::from(path))
+        };
+        Ok(Self {
+            prefix: prefix.to_owned(),
+            backend,
+            writable,
+        })
+    }
+}
+
+impl fmt::Display for Mount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.backend {
+            Backend::Directory(path) => write!(f, "{}=fs:{}", self.prefix, path.display())?,
+            Backend::Memory => write!(f, "{}=memory:", self.prefix)?,
+            Backend::Tar(path) => write!(f, "{}=tar:{}", self.prefix, path.display())?,
         }
+        write!(f, ",{}", if self.writable { "rw" } else { "ro" })
     }
-    let directory = if !directory.is_empty() {
-        Arc::new(directory)
-    } else {
-        Arc::new("./".to_owned())
-    };
+}
 
-    let listener = TcpListener::bind("127.0.0.1:4221")?;
+/// What the accept loop does when every worker is busy and the queue of
+/// waiting connections is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum QueuePolicy {
+    /// Stop accepting until a slot frees up.
+    Block,
+    /// Answer 503 straight away.
+    Reject,
+}
 
-    for connection in listener.incoming() {
-        match connection {
-            Ok(conn) => {
-                let dir = directory.clone();
-                thread::spawn(move || {
-                    if let Err(err) = connection_handler(conn, dir) {
-                        eprintln!("Connection handler error: {}", err);
-                    }
-                });
-            }
-            Err(err) => {
-                eprintln!("Failed to accept connection: {}", err);
-            }
+impl fmt::Display for QueuePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueuePolicy::Block => write!(f, "block"),
+            QueuePolicy::Reject => write!(f, "reject"),
         }
     }
+}
 
-    Ok(())
+impl FromStr for QueuePolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(QueuePolicy::Block),
+            "reject" => Ok(QueuePolicy::Reject),
+            _ => Err(()),
+        }
+    }
 }
 
-pub type Error = Box<dyn std::error::Error + Send + Sync>;
+#[derive(Debug)]
+struct Config {
+    host: String,
+    port: u16,
+    directory: PathBuf,
+    create_directory: bool,
+    default_mime: String,
+    poll_timeout: Duration,
+    cors_expose_headers: Option<String>,
+    threads: usize,
+    stream_threshold: u64,
+    strict_bodies: bool,
+    allow_upload: bool,
+    enabled_encodings: Vec<EncodingType>,
+    follow_symlinks: bool,
+    deny_dotfiles: bool,
+    well_known_dir: Option<PathBuf>,
+    max_conn_per_ip: Option<usize>,
+    max_body_size: u64,
+    max_request_line: usize,
+    max_header_line: usize,
+    max_header_bytes: usize,
+    max_header_count: usize,
+    max_echo_length: usize,
+    echo_disposition: bool,
+    limits_path: String,
+    slow_request_threshold: Option<Duration>,
+    server_name: Option<String>,
+    keepalive_timeout: Duration,
+    max_connection_duration: Option<Duration>,
+    read_timeout: Duration,
+    write_timeout: Duration,
+    mounts: Vec<Mount>,
+    queue_size: usize,
+    queue_policy: QueuePolicy,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            host: "127.0.0.1".to_owned(),
+            port: 4221,
+            directory: PathBuf::from("./"),
+            create_directory: false,
+            default_mime: "application/octet-stream".to_owned(),
+            poll_timeout: Duration::from_secs(30),
+            cors_expose_headers: None,
+            threads: thread::available_parallelism().map_or(4, |n| n.get().max(MIN_THREADS)),
+            stream_threshold: 4 * 1024 * 1024,
+            strict_bodies: false,
+            allow_upload: false,
+            enabled_encodings: SUPPORTED_ENCODINGS.to_vec(),
+            follow_symlinks: true,
+            deny_dotfiles: false,
+            well_known_dir: None,
+            max_conn_per_ip: None,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            max_request_line: DEFAULT_MAX_REQUEST_LINE,
+            max_header_line: DEFAULT_MAX_HEADER_LINE,
+            max_header_bytes: DEFAULT_MAX_HEADER_BYTES,
+            max_header_count: DEFAULT_MAX_HEADER_COUNT,
+            max_echo_length: 4096,
+            echo_disposition: false,
+            limits_path: "/.well-known/server-limits".to_owned(),
+            slow_request_threshold: None,
+            server_name: Some(format!("vinhtc27-http/{}", env!("CARGO_PKG_VERSION"))),
+            keepalive_timeout: Duration::from_secs(5),
+            max_connection_duration: None,
+            read_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(30),
+            mounts: Vec::new(),
+            queue_size: 128,
+            queue_policy: QueuePolicy::Block,
+        }
+    }
+}
+
+impl Config {
+    fn limits(&self) -> Limits {
+        Limits {
+            request_line: self.max_request_line,
+            header_line: self.max_header_line,
+            header_bytes: self.max_header_bytes,
+            header_count: self.max_header_count,
+            body: self.max_body_size,
+        }
+    }
+
+    /// Renders the effective settings as JSON, keyed by command-line flag.
+    /// Each entry records whether its value came from the command line or
+    /// is the built-in default.
+    fn to_json(&self, from_cli: &HashSet<String>) -> String {
+        let optional = |value: Option<String>| value.unwrap_or_else(|| "null".to_owned());
+        let encodings = |encodings: &[EncodingType]| {
+            let names: Vec<_> = encodings
+                .iter()
+                .map(|encoding| json_string(&encoding.to_string()))
+                .collect();
+            format!("[{}]", names.join(", "))
+        };
+
+        let entries: Vec<(&str, &[&str], String)> = vec![
+            ("host", &["host"], json_string(&self.host)),
+            ("port", &["port"], self.port.to_string()),
+            (
+                "directory",
+                &["directory"],
+                json_string(&self.directory.to_string_lossy()),
+            ),
+            (
+                "create-directory",
+                &["create-directory"],
+                self.create_directory.to_string(),
+            ),
+            (
+                "allow-upload",
+                &["allow-upload"],
+                self.allow_upload.to_string(),
+            ),
+            (
+                "follow-symlinks",
+                &["no-follow-symlinks"],
+                self.follow_symlinks.to_string(),
+            ),
+            (
+                "deny-dotfiles",
+                &["deny-dotfiles"],
+                self.deny_dotfiles.to_string(),
+            ),
+            (
+                "well-known-dir",
+                &["well-known-dir"],
+                optional(
+                    self.well_known_dir
+                        .as_ref()
+                        .map(|dir| json_string(&dir.to_string_lossy())),
+                ),
+            ),
+            (
+                "default-mime",
+                &["default-mime"],
+                json_string(&self.default_mime),
+            ),
+            (
+                "enable-encoding",
+                &["enable-encoding"],
+                encodings(&self.enabled_encodings),
+            ),
+            (
+                "stream-threshold",
+                &["stream-threshold"],
+                self.stream_threshold.to_string(),
+            ),
+            (
+                "strict-bodies",
+                &["strict-bodies"],
+                self.strict_bodies.to_string(),
+            ),
+            (
+                "cors-expose-headers",
+                &["cors-expose-headers"],
+                optional(self.cors_expose_headers.as_deref().map(json_string)),
+            ),
+            ("threads", &["threads"], self.threads.to_string()),
+            ("queue-size", &["queue-size"], self.queue_size.to_string()),
+            (
+                "queue-full",
+                &["queue-full"],
+                json_string(&self.queue_policy.to_string()),
+            ),
+            (
+                "max-conn-per-ip",
+                &["max-conn-per-ip"],
+                optional(self.max_conn_per_ip.map(|limit| limit.to_string())),
+            ),
+            (
+                "max-body-size",
+                &["max-body-size"],
+                self.max_body_size.to_string(),
+            ),
+            (
+                "max-request-line",
+                &["max-request-line"],
+                self.max_request_line.to_string(),
+            ),
+            (
+                "max-header-line",
+                &["max-header-line"],
+                self.max_header_line.to_string(),
+            ),
+            (
+                "max-header-bytes",
+                &["max-header-bytes"],
+                self.max_header_bytes.to_string(),
+            ),
+            (
+                "max-header-count",
+                &["max-header-count"],
+                self.max_header_count.to_string(),
+            ),
+            (
+                "max-echo-length",
+                &["max-echo-length"],
+                self.max_echo_length.to_string(),
+            ),
+            (
+                "echo-content-disposition",
+                &["echo-content-disposition"],
+                self.echo_disposition.to_string(),
+            ),
+            (
+                "limits-path",
+                &["limits-path"],
+                json_string(&self.limits_path),
+            ),
+            (
+                "poll-timeout-secs",
+                &["poll-timeout-secs"],
+                self.poll_timeout.as_secs().to_string(),
+            ),
+            (
+                "log-slow-requests",
+                &["log-slow-requests"],
+                optional(
+                    self.slow_request_threshold
+                        .map(|threshold| threshold.as_millis().to_string()),
+                ),
+            ),
+            (
+                "server-name",
+                &["server-name", "no-server-header"],
+                optional(self.server_name.as_deref().map(json_string)),
+            ),
+            (
+                "keepalive-timeout",
+                &["keepalive-timeout"],
+                self.keepalive_timeout.as_secs().to_string(),
+            ),
+            (
+                "max-connection-duration",
+                &["max-connection-duration"],
+                optional(
+                    self.max_connection_duration
+                        .map(|duration| duration.as_secs().to_string()),
+                ),
+            ),
+            (
+                "read-timeout-secs",
+                &["read-timeout-secs"],
+                self.read_timeout.as_secs().to_string(),
+            ),
+            (
+                "write-timeout-secs",
+                &["write-timeout-secs"],
+                self.write_timeout.as_secs().to_string(),
+            ),
+            ("mount", &["mount"], {
+                let mounts: Vec<_> = self
+                    .mounts
+                    .iter()
+                    .map(|mount| json_string(&mount.to_string()))
+                    .collect();
+                format!("[{}]", mounts.join(", "))
+            }),
+            ("compiled-encodings", &[], encodings(&SUPPORTED_ENCODINGS)),
+        ];
+
+        let fields: Vec<_> = entries
+            .iter()
+            .map(|(key, flags, value)| {
+                let source = if flags.iter().any(|flag| from_cli.contains(*flag)) {
+                    "cli"
+                } else {
+                    "default"
+                };
+                format!(
+                    "  {}: {{\"value\": {}, \"source\": \"{}\"}}",
+                    json_string(key),
+                    value,
+                    source
+                )
+            })
+            .collect();
+        format!("{{\n{}\n}}", fields.join(",\n"))
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Fixed set of worker threads pulling jobs off a shared, bounded queue.
+struct ThreadPool {
+    workers: Vec<JoinHandle<()>>,
+    sender: Option<SyncSender<Job>>,
+    queued: Arc<AtomicUsize>,
+    capacity: usize,
+}
+
+impl ThreadPool {
+    fn new(size: usize, capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        let (sender, receiver) = mpsc::sync_channel::<Job>(capacity);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let queued = Arc::new(AtomicUsize::new(0));
+
+        let workers = (0..size.max(1))
+            .map(|_| {
+                let receiver = receiver.clone();
+                let queued = queued.clone();
+                thread::spawn(move || loop {
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => {
+                            queued.fetch_sub(1, Ordering::Relaxed);
+                            // A panicking job must not take its worker
+                            // with it, or the pool slowly runs dry.
+                            if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                                eprintln!("Worker job panicked");
+                            }
+                        }
+                        Err(_) => break,
+                    }
+                })
+            })
+            .collect();
+
+        Self {
+            workers,
+            sender: Some(sender),
+            queued,
+            capacity,
+        }
+    }
+
+    /// Queues a job, blocking while the queue is full.
+    fn execute<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Some(sender) = &self.sender {
+            self.queued.fetch_add(1, Ordering::Relaxed);
+            if sender.send(Box::new(job)).is_err() {
+                self.queued.fetch_sub(1, Ordering::Relaxed);
+                eprintln!("Dropping job: no workers left");
+            }
+        }
+    }
+
+    /// Whether `execute` would block right now. Only meaningful when a
+    /// single thread submits jobs, as the accept loop does.
+    fn is_full(&self) -> bool {
+        self.queued.load(Ordering::Relaxed) >= self.capacity
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // Closing the queue lets each worker finish its job and exit.
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Counts open connections per client IP so one address can't take over
+/// every worker. Slots are handed out as guards that give themselves back
+/// when the connection is dropped.
+#[derive(Debug)]
+struct ConnectionLimiter {
+    limit: Option<usize>,
+    counts: Mutex<HashMap<IpAddr, usize>>,
+}
+
+struct ConnectionSlot {
+    limiter: Arc<ConnectionLimiter>,
+    ip: IpAddr,
+}
+
+impl ConnectionLimiter {
+    fn new(limit: Option<usize>) -> Self {
+        Self {
+            limit,
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn try_acquire(self: &Arc<Self>, ip: IpAddr) -> Option<ConnectionSlot> {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(ip).or_default();
+        if self.limit.is_some_and(|limit| *count >= limit) {
+            return None;
+        }
+        *count += 1;
+        Some(ConnectionSlot {
+            limiter: self.clone(),
+            ip,
+        })
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut counts = self.limiter.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
+
+type PollWaiter = (u64, Sender<Vec<u8>>);
+
+/// Parks `/poll` clients until `/notify` pushes a message for them or the
+/// wait times out. Each waiter is tagged so a timed-out request doesn't
+/// unregister a newer poll for the same client id.
+///
+/// A waiting poll holds a pool worker, so at most `max_waiters` may wait
+/// at once; the pool is sized so that leaves a worker free for `/notify`.
+#[derive(Debug)]
+struct LongPollHandler {
+    clients: Mutex<HashMap<String, PollWaiter>>,
+    next_waiter: AtomicU64,
+    waiting: AtomicUsize,
+    max_waiters: usize,
+}
+
+impl LongPollHandler {
+    fn new(max_waiters: usize) -> Self {
+        Self {
+            clients: Mutex::new(HashMap::new()),
+            next_waiter: AtomicU64::new(0),
+            waiting: AtomicUsize::new(0),
+            max_waiters,
+        }
+    }
+
+    /// Waits for a message, or fails straight away with 503 when every
+    /// waiting slot is taken.
+    fn wait(&self, client_id: &str, timeout: Duration) -> Result<Option<Vec<u8>>, HttpError> {
+        if self
+            .waiting
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |waiting| {
+                (waiting < self.max_waiters).then_some(waiting + 1)
+            })
+            .is_err()
+        {
+            return Err(HttpError::Unavailable);
+        }
+        let waiter = self.next_waiter.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = mpsc::channel();
+        self.clients
+            .lock()
+            .unwrap()
+            .insert(client_id.to_owned(), (waiter, sender));
+
+        let message = receiver.recv_timeout(timeout).ok();
+
+        let mut clients = self.clients.lock().unwrap();
+        if clients.get(client_id).is_some_and(|(id, _)| *id == waiter) {
+            clients.remove(client_id);
+        }
+        self.waiting.fetch_sub(1, Ordering::SeqCst);
+        Ok(message)
+    }
+
+    fn notify(&self, client_id: &str, message: Vec<u8>) -> bool {
+        match self.clients.lock().unwrap().remove(client_id) {
+            Some((_, sender)) => sender.send(message).is_ok(),
+            None => false,
+        }
+    }
+}
+
+struct UserAgentInfo {
+    user_agent: String,
+}
+
+impl Render for UserAgentInfo {
+    fn text(&self) -> String {
+        self.user_agent.clone()
+    }
+
+    fn html(&self) -> String {
+        format!(
+            "<!DOCTYPE html>\n<html><body><p>{}</p></body></html>\n",
+            html_escape(&self.user_agent)
+        )
+    }
+
+    fn json(&self) -> String {
+        format!("{{\"user_agent\": {}}}", json_string(&self.user_agent))
+    }
+}
+
+/// Maps a file name's extension to its MIME type, or `None` for a missing
+/// or unrecognized extension so the caller can apply `--default-mime`.
+fn content_type_for(path: &str) -> Option<&'static str> {
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+
+    let mime = match extension.as_str() {
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "js" | "mjs" => "text/javascript",
+        "json" => "application/json",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "txt" => "text/plain",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        _ => return None,
+    };
+    Some(mime)
+}
+
+/// A weak validator built from the modification time, size and any backend
+/// identity, cheap enough to compute on every request.
+fn file_etag(info: &FileInfo) -> Option<String> {
+    let modified = info.modified?.duration_since(UNIX_EPOCH).ok()?;
+    Some(match info.identity {
+        Some(identity) => format!(
+            "W/\"{:x}-{:x}-{:x}\"",
+            modified.as_nanos(),
+            info.len,
+            identity
+        ),
+        None => format!("W/\"{:x}-{:x}\"", modified.as_nanos(), info.len),
+    })
+}
+
+/// Whole seconds since the epoch, the resolution HTTP dates carry.
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// Checks an `If-Match` / `If-None-Match` list against the current tag,
+/// `None` meaning the resource doesn't exist. Tags are compared weakly,
+/// ignoring the `W/` prefix, since the only tags we hand out are weak.
+fn etag_matches(condition: &str, etag: Option<&str>) -> bool {
+    let Some(etag) = etag else {
+        return false;
+    };
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_owned();
+    condition.trim() == "*" || condition.split(',').any(|tag| opaque(tag) == opaque(etag))
+}
+
+/// A connection the server can speak HTTP over.
+trait Stream: Read + Write + Send + PeerAddr {
+    /// Bounds how long a single read may block; `None` waits forever.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    /// Bounds how long a single write may block; `None` waits forever.
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Stream for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+}
+
+#[cfg(unix)]
+impl Stream for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+}
+
+/// A stream whose reads give up at a fixed deadline when one is set, and
+/// otherwise after `timeout` per read. A per-read timeout alone lets a
+/// client hold a connection forever by trickling a byte at a time.
+struct DeadlineStream<S> {
+    inner: S,
+    timeout: Duration,
+    deadline: Option<Instant>,
+}
+
+impl<S: Stream> Read for DeadlineStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut timeout = self.timeout;
+        if let Some(deadline) = self.deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(ErrorKind::TimedOut.into());
+            }
+            timeout = timeout.min(remaining);
+        }
+        self.inner.set_read_timeout(Some(timeout))?;
+        self.inner.read(buf)
+    }
+}
+
+impl<S: Stream> Write for DeadlineStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn is_timeout(err: &io::Error) -> bool {
+    matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+/// The remote end of a connection, where the transport has one.
+trait PeerAddr {
+    fn peer_addr(&self) -> Option<SocketAddr>;
+}
+
+impl PeerAddr for TcpStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+}
+
+/// Unix sockets have no IP address to report.
+#[cfg(unix)]
+impl PeerAddr for UnixStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+fn connection_handler<S: Stream>(
+    conn: S,
+    config: Arc<Config>,
+    router: Arc<Router>,
+) -> Result<(), Error> {
+    let peer = PeerAddr::peer_addr(&conn)
+        .map_or_else(|| "unknown peer".to_owned(), |addr| addr.to_string());
+    let expires = config
+        .max_connection_duration
+        .map(|duration| Instant::now() + duration);
+    conn.set_write_timeout(Some(config.write_timeout))?;
+    // Responses go out through the same buffered stream; anything read
+    // ahead stays buffered for the next request.
+    let mut reader = BufReader::new(DeadlineStream {
+        inner: conn,
+        timeout: config.read_timeout,
+        deadline: None,
+    });
+
+    let mut idle = false;
+    loop {
+        if idle && reader.buffer().is_empty() {
+            // Between requests the client gets the keep-alive timeout to
+            // start the next one; after that the connection closes quietly.
+            reader.get_mut().timeout = config.keepalive_timeout;
+            match reader.fill_buf() {
+                Ok([]) => return Ok(()),
+                Ok(_) => {}
+                Err(err) if is_timeout(&err) => return Ok(()),
+                Err(err) => return Err(err.into()),
+            }
+            reader.get_mut().timeout = config.read_timeout;
+        }
+
+        // The whole head shares one deadline; the body only gets the
+        // per-read timeout, and running out of it just drops the connection.
+        reader.get_mut().deadline = Some(Instant::now() + config.read_timeout);
+        let limits = config.limits();
+        let parsed = HttpRequest::parse_head(&mut reader, &limits).and_then(|mut request| {
+            reader.get_mut().deadline = None;
+            request.body = read_body(&mut reader, &mut request.headers, false, &limits)?;
+            Ok(request)
+        });
+        let request = match parsed {
+            Ok(request) => request,
+            Err(ParseError::ConnectionClosed) => return Ok(()),
+            Err(ParseError::Io(err)) if is_timeout(&err) => {
+                if reader.get_ref().deadline.is_none() {
+                    return Ok(());
+                }
+                write_parse_error(reader.get_mut(), &ParseError::Timeout, &config)?;
+                return Ok(());
+            }
+            Err(err) => {
+                write_parse_error(reader.get_mut(), &err, &config)?;
+                return Err(err.into());
+            }
+        };
+
+        // A connection past its maximum lifetime is served once more and
+        // then closed, so the client sees `Connection: close`.
+        let keep_alive =
+            request.keep_alive() && expires.is_none_or(|expires| Instant::now() < expires);
+        let head = request.method == Method::Head;
+        let method = request.method;
+        let path = request.target.path.clone();
+        let started = Instant::now();
+        let mut response = handle_request(request, &config, &router);
+        let elapsed = started.elapsed();
+        if config
+            .slow_request_threshold
+            .is_some_and(|threshold| elapsed > threshold)
+        {
+            eprintln!(
+                "WARN slow request from {}: {} {} took {}ms",
+                peer,
+                method,
+                path,
+                elapsed.as_millis()
+            );
+        }
+        if head {
+            // Same headers as the GET would carry, Content-Length or
+            // Transfer-Encoding included.
+            response.body = Body::Bytes(Vec::new());
+        }
+        response.append_list_value(
+            HeaderType::Connection,
+            if keep_alive { "keep-alive" } else { "close" },
+        );
+        // A handler may have asked for the connection to close.
+        let keep_alive = response.headers[&HeaderType::Connection]
+            .split(',')
+            .all(|token| !token.trim().eq_ignore_ascii_case("close"));
+        if let Err(err) = response.write_to(reader.get_mut()) {
+            // A client that stops reading is dropped without further noise.
+            if err.downcast_ref::<io::Error>().is_some_and(is_timeout) {
+                return Ok(());
+            }
+            return Err(err);
+        }
+
+        if !keep_alive {
+            return Ok(());
+        }
+        idle = true;
+    }
+}
+
+/// Answers a request that could not be parsed. The connection is closed
+/// afterwards, since the framing of anything after it is unknown.
+fn write_parse_error<W: Write>(
+    writer: &mut W,
+    err: &ParseError,
+    config: &Config,
+) -> Result<(), Error> {
+    let body = err.to_string().into_bytes();
+    let mut headers = HashMap::new();
+    // Parse errors can quote the offending method or target back.
+    headers.insert(HeaderType::ContentType, "text/plain".to_owned());
+    headers.insert(HeaderType::XContentTypeOptions, "nosniff".to_owned());
+    headers.insert(HeaderType::ContentLength, body.len().to_string());
+    headers.insert(HeaderType::Connection, "close".to_owned());
+    if let Some(server_name) = &config.server_name {
+        headers.insert(HeaderType::Server, server_name.clone());
+    }
+    let mut response = HttpResponse {
+        version: "HTTP/1.1".to_owned(),
+        status_code: err.status_code(),
+        headers,
+        body: body.into(),
+    };
+    response.write_to(writer)
+}
+
+fn handle_request(mut request: HttpRequest, config: &Config, router: &Router) -> HttpResponse {
+    // The parser has already consumed any body, so framing stays intact
+    // whether we drop it here or refuse the request outright.
+    let unexpected_body = matches!(request.method, Method::Get | Method::Head | Method::Delete)
+        && !request.body.is_empty();
+    if unexpected_body && !config.strict_bodies {
+        request.body.clear();
+    }
+
+    let mut response = if unexpected_body && config.strict_bodies {
+        let mut response = HttpResponse::new(StatusCode::BadRequest);
+        response.body = format!("request body not allowed on {}", request.method).into();
+        response
+    } else {
+        router.dispatch(&mut request)
+    };
+    if let Err(err) = response.check_content_range() {
+        eprintln!("Refusing inconsistent response: {}", err);
+        response = HttpResponse::new(StatusCode::InternalServerError);
+    }
+    response.version = request.version.clone();
+
+    let preflight = matches!(request.method, Method::Options)
+        && request
+            .headers
+            .contains_key(&HeaderType::AccessControlRequestMethod);
+    if let Some(expose_headers) = &config.cors_expose_headers {
+        // Whether the header is sent depends on Origin, so caches must key on it.
+        response.append_list_value(HeaderType::Vary, &HeaderType::Origin.to_string());
+        if request.headers.contains_key(&HeaderType::Origin) && !preflight {
+            response.headers.insert(
+                HeaderType::AccessControlExposeHeaders,
+                expose_headers.clone(),
+            );
+        }
+    }
+
+    if let Some(server_name) = &config.server_name {
+        response
+            .headers
+            .entry(HeaderType::Server)
+            .or_insert_with(|| server_name.clone());
+    }
+
+    // 204 must not carry a length, and on a 304 it would describe the
+    // unsent representation rather than the empty body.
+    if !matches!(
+        response.status_code,
+        StatusCode::NoContent | StatusCode::NotModified
+    ) {
+        match response.body.len() {
+            Some(len) => {
+                response
+                    .headers
+                    .insert(HeaderType::ContentLength, len.to_string());
+            }
+            // An HTTP/1.0 client can't decode chunked framing, so the end
+            // of the body is marked by closing the connection instead.
+            None if request.version == "HTTP/1.0" => {
+                response.append_list_value(HeaderType::Connection, "close");
+            }
+            None => {
+                response
+                    .headers
+                    .insert(HeaderType::TransferEncoding, "chunked".to_owned());
+            }
+        }
+    }
+
+    response
+}
+
+fn build_router(config: &Arc<Config>, long_poll: &Arc<LongPollHandler>) -> Result<Router, Error> {
+    let mut router = Router::new();
+    router.get("/", |_| Ok(HttpResponse::new(StatusCode::Ok)));
+    router.get("/user-agent", user_agent);
+
+    let limits_config = config.clone();
+    router.get(&config.limits_path, move |_| {
+        Ok(server_limits(&limits_config))
+    });
+
+    let echo_config = config.clone();
+    router.get("/echo/:message", move |request| echo(request, &echo_config));
+
+    let files = Mount {
+        prefix: "/files".to_owned(),
+        backend: Backend::Directory(config.directory.clone()),
+        writable: config.allow_upload,
+    };
+    // `.well-known` is a registered prefix, so it is served even when
+    // dotfiles are hidden everywhere else.
+    let well_known = config.well_known_dir.as_ref().map(|dir| Mount {
+        prefix: "/.well-known".to_owned(),
+        backend: Backend::Directory(dir.clone()),
+        writable: false,
+    });
+    for mount in std::iter::once(&files)
+        .chain(&well_known)
+        .chain(&config.mounts)
+    {
+        let mut storage: Arc<dyn Storage> = match &mount.backend {
+            Backend::Directory(root) => {
+                Arc::new(DirectoryStorage::new(root.clone(), config.follow_symlinks))
+            }
+            Backend::Memory => Arc::new(MemoryStorage::default()),
+            Backend::Tar(archive) => Arc::new(
+                TarStorage::open_archive(archive.clone())
+                    .map_err(|err| format!("cannot mount {}: {}", mount, err))?,
+            ),
+        };
+        if config.deny_dotfiles {
+            storage = Arc::new(HideDotfiles(storage));
+        }
+        let pattern = format!("{}/*name", mount.prefix.trim_end_matches('/'));
+
+        let (files_config, files_storage) = (config.clone(), storage.clone());
+        router.get(&pattern, move |request| {
+            get_file(request, &files_config, files_storage.as_ref())
+        });
+        if mount.writable {
+            for method in [Method::Post, Method::Put] {
+                let files_storage = storage.clone();
+                router.route(method, &pattern, move |request| {
+                    put_file(request, files_storage.as_ref())
+                });
+            }
+            router.route(Method::Delete, &pattern, move |request| {
+                delete_file(request, storage.as_ref())
+            });
+        }
+    }
+
+    let poll_config = config.clone();
+    let poll_waiters = long_poll.clone();
+    router.get("/poll", move |request| {
+        poll(request, &poll_config, &poll_waiters)
+    });
+    let notify_waiters = long_poll.clone();
+    router.post("/notify/:client_id", move |request| {
+        notify(request, &notify_waiters)
+    });
+
+    Ok(router)
+}
+
+/// Picks a content coding from `Accept-Encoding`. Fails with 406 when the
+/// client refuses identity and none of the enabled codings are acceptable.
+fn negotiate_encoding(
+    request: &HttpRequest,
+    enabled: &[EncodingType],
+) -> Result<Option<EncodingType>, HttpError> {
+    let Some(accept) = request.headers.get(&HeaderType::AcceptEncoding) else {
+        return Ok(None);
+    };
+    match select_encoding(accept, enabled) {
+        Some(encoding) => Ok(Some(encoding)),
+        None if identity_refused(accept) => Err(HttpError::NotAcceptable),
+        None => Ok(None),
+    }
+}
+
+fn echo(request: &HttpRequest, config: &Config) -> Result<HttpResponse, HttpError> {
+    let encoding = negotiate_encoding(request, &config.enabled_encodings)?;
+    let message = request.param("message").unwrap_or_default();
+    if message.len() > config.max_echo_length {
+        return Err(HttpError::UriTooLong);
+    }
+
+    // The body is whatever the client put in the URL, so keep browsers from
+    // sniffing it into something executable.
+    let mut response = HttpResponse::new(StatusCode::Ok);
+    response
+        .headers
+        .insert(HeaderType::ContentType, "text/plain".to_owned());
+    response
+        .headers
+        .insert(HeaderType::XContentTypeOptions, "nosniff".to_owned());
+    if config.echo_disposition {
+        response.headers.insert(
+            HeaderType::ContentDisposition,
+            "inline; filename=\"echo.txt\"".to_owned(),
+        );
+    }
+    response.set_encoded_body(encoding, message.into())?;
+    Ok(response)
+}
+
+/// Reports the limits a client has to stay within, as JSON.
+fn server_limits(config: &Config) -> HttpResponse {
+    let max_conn_per_ip = config
+        .max_conn_per_ip
+        .map_or("null".to_owned(), |limit| limit.to_string());
+    let encodings = config
+        .enabled_encodings
+        .iter()
+        .map(|encoding| json_string(&encoding.to_string()))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let mut response = HttpResponse::new(StatusCode::Ok);
+    response
+        .headers
+        .insert(HeaderType::ContentType, "application/json".to_owned());
+    response.body = format!(
+        "{{\"max_body_size\": {}, \"max_request_line\": {}, \"max_header_bytes\": {}, \"max_header_count\": {}, \"max_echo_length\": {}, \"read_timeout_secs\": {}, \"poll_timeout_secs\": {}, \"max_pollers\": {}, \"max_conn_per_ip\": {}, \"encodings\": [{}]}}",
+        config.max_body_size,
+        config.max_request_line,
+        config.max_header_bytes,
+        config.max_header_count,
+        config.max_echo_length,
+        config.read_timeout.as_secs(),
+        config.poll_timeout.as_secs(),
+        config.threads.saturating_sub(1),
+        max_conn_per_ip,
+        encodings
+    )
+    .into();
+    response
+}
+
+fn user_agent(request: &HttpRequest) -> Result<HttpResponse, HttpError> {
+    let info = UserAgentInfo {
+        user_agent: request
+            .headers
+            .get(&HeaderType::UserAgent)
+            .cloned()
+            .unwrap_or_default(),
+    };
+    let representation = Representation::negotiate(
+        request.headers.get(&HeaderType::Accept).map(String::as_str),
+        Representation::Text,
+    );
+
+    let mut response = HttpResponse::new(StatusCode::Ok);
+    response.headers.insert(
+        HeaderType::ContentType,
+        representation.media_type().to_owned(),
+    );
+    response.append_list_value(HeaderType::Vary, &HeaderType::Accept.to_string());
+    response.body = info.render(representation).into();
+    Ok(response)
+}
+
+/// How a `Range` header applies to a resource of known length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ByteRange {
+    /// No single byte range we can honour; send the whole resource.
+    Full,
+    /// Inclusive first and last byte positions.
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+impl ByteRange {
+    /// Interprets a `Range` value against a resource of `len` bytes. Only a
+    /// single `bytes` range is served; multiple ranges, other units and
+    /// malformed values are ignored and fall back to the full resource.
+    fn parse(range: &str, len: u64) -> Self {
+        let Some((unit, spec)) = range.split_once('=') else {
+            return ByteRange::Full;
+        };
+        if !unit.trim().eq_ignore_ascii_case("bytes") || spec.contains(',') {
+            return ByteRange::Full;
+        }
+        let Some((first, last)) = spec.trim().split_once('-') else {
+            return ByteRange::Full;
+        };
+        let parse = |pos: &str| {
+            (!pos.is_empty() && pos.bytes().all(|b| b.is_ascii_digit()))
+                .then(|| pos.parse::<u64>().ok())
+                .flatten()
+        };
+
+        if first.is_empty() {
+            // Suffix range: the last `n` bytes.
+            return match parse(last) {
+                Some(0) => ByteRange::Unsatisfiable,
+                Some(_) if len == 0 => ByteRange::Unsatisfiable,
+                Some(n) => ByteRange::Partial(len.saturating_sub(n), len - 1),
+                None => ByteRange::Full,
+            };
+        }
+
+        let Some(start) = parse(first) else {
+            return ByteRange::Full;
+        };
+        let end = if last.is_empty() {
+            u64::MAX
+        } else {
+            match parse(last) {
+                Some(end) if end >= start => end,
+                _ => return ByteRange::Full,
+            }
+        };
+        if start >= len {
+            ByteRange::Unsatisfiable
+        } else {
+            ByteRange::Partial(start, end.min(len - 1))
+        }
+    }
+}
+
+/// The storage name a files route captured. A segment holding an encoded
+/// `/` can't name a file, and joined into the name it would pass for a
+/// directory, so it is refused.
+fn file_name(request: &HttpRequest) -> Result<&str, HttpError> {
+    if request.rest.iter().any(|segment| segment.contains('/')) {
+        return Err(StorageError::Forbidden.into());
+    }
+    Ok(request.param("name").unwrap_or_default())
+}
+
+fn get_file(
+    request: &HttpRequest,
+    config: &Config,
+    storage: &dyn Storage,
+) -> Result<HttpResponse, HttpError> {
+    // A directory URL serves its index page.
+    let mut name = file_name(request)?.to_owned();
+    if name.is_empty() || name.ends_with('/') {
+        name.push_str("index.html");
+    }
+    let name = name.as_str();
+    let (mut file, info) = storage.open(name)?;
+    let len = info.len;
+    let etag = file_etag(&info);
+    let modified = info.modified;
+    let mut validators = Vec::new();
+    if let Some(etag) = &etag {
+        validators.push((HeaderType::ETag, etag.clone()));
+    }
+    if let Some(modified) = modified {
+        validators.push((HeaderType::LastModified, format_http_date(modified)));
+    }
+
+    // If-Modified-Since is only consulted when there is no If-None-Match,
+    // and an unparseable date leaves the request unconditional.
+    let not_modified = match request.headers.get(&HeaderType::IfNoneMatch) {
+        Some(condition) => etag_matches(condition, etag.as_deref()),
+        None => request
+            .headers
+            .get(&HeaderType::IfModifiedSince)
+            .and_then(|since| parse_http_date(since))
+            .zip(modified)
+            .is_some_and(|(since, modified)| unix_secs(modified) <= unix_secs(since)),
+    };
+    // Every answer below stands in for a representation chosen by
+    // Accept-Encoding, even the ones sent uncompressed, so caches must key
+    // on it throughout.
+    let vary = HeaderType::AcceptEncoding.to_string();
+    if not_modified {
+        let mut response = HttpResponse::new(StatusCode::NotModified);
+        response.headers.extend(validators);
+        response.append_list_value(HeaderType::Vary, &vary);
+        return Ok(response);
+    }
+    let encoding = negotiate_encoding(request, &config.enabled_encodings)?;
+
+    let content_type = content_type_for(name).unwrap_or(&config.default_mime);
+    let mut response = HttpResponse::new(StatusCode::Ok);
+    response
+        .headers
+        .insert(HeaderType::ContentType, content_type.to_owned());
+    response.headers.extend(validators);
+    response.append_list_value(HeaderType::Vary, &vary);
+
+    let range = request
+        .headers
+        .get(&HeaderType::Range)
+        .map_or(ByteRange::Full, |range| ByteRange::parse(range, len));
+    match range {
+        ByteRange::Full => {}
+        ByteRange::Partial(start, end) => {
+            // Ranges address the identity bytes, so slices are never compressed.
+            file.seek(SeekFrom::Start(start))?;
+            response.status_code = StatusCode::PartialContent;
+            response.headers.insert(
+                HeaderType::ContentRange,
+                format!("bytes {}-{}/{}", start, end, len),
+            );
+            response.body = Body::Reader {
+                reader: Box::new(file),
+                len: end - start + 1,
+            };
+            return Ok(response);
+        }
+        ByteRange::Unsatisfiable => {
+            let mut response = HttpResponse::new(StatusCode::RangeNotSatisfiable);
+            response
+                .headers
+                .insert(HeaderType::ContentRange, format!("bytes */{}", len));
+            response.append_list_value(HeaderType::Vary, &vary);
+            return Ok(response);
+        }
+    }
+
+    response
+        .headers
+        .insert(HeaderType::AcceptRanges, "bytes".to_owned());
+    if len > config.stream_threshold {
+        // Too big to buffer, so it goes out as-is without compression.
+        response.body = Body::Reader {
+            reader: Box::new(file),
+            len,
+        };
+    } else {
+        let mut contents = Vec::with_capacity(len as usize);
+        file.read_to_end(&mut contents)?;
+        response.set_encoded_body(encoding, contents)?;
+    }
+    Ok(response)
+}
+
+/// Evaluates `If-Match` against the stored file before it is changed.
+fn precondition_failed(request: &HttpRequest, storage: &dyn Storage, name: &str) -> bool {
+    request
+        .headers
+        .get(&HeaderType::IfMatch)
+        .is_some_and(|condition| {
+            let etag = storage.stat(name).ok().and_then(|info| file_etag(&info));
+            !etag_matches(condition, etag.as_deref())
+        })
+}
+
+fn put_file(request: &HttpRequest, storage: &dyn Storage) -> Result<HttpResponse, HttpError> {
+    let name = file_name(request)?;
+    if precondition_failed(request, storage, name) {
+        return Err(HttpError::PreconditionFailed);
+    }
+    storage.replace(name, &request.body)?;
+    Ok(HttpResponse::new(StatusCode::Created))
+}
+
+fn delete_file(request: &HttpRequest, storage: &dyn Storage) -> Result<HttpResponse, HttpError> {
+    let name = file_name(request)?;
+    if precondition_failed(request, storage, name) {
+        return Err(HttpError::PreconditionFailed);
+    }
+    storage.delete(name)?;
+    Ok(HttpResponse::new(StatusCode::NoContent))
+}
+
+fn poll(
+    request: &HttpRequest,
+    config: &Config,
+    long_poll: &LongPollHandler,
+) -> Result<HttpResponse, HttpError> {
+    let client_id = request
+        .query_param("client_id")
+        .ok_or(HttpError::BadRequest)?;
+    match long_poll.wait(client_id, config.poll_timeout)? {
+        Some(message) => {
+            let mut response = HttpResponse::new(StatusCode::Ok);
+            response.body = message.into();
+            Ok(response)
+        }
+        None => Ok(HttpResponse::new(StatusCode::NoContent)),
+    }
+}
+
+fn notify(request: &HttpRequest, long_poll: &LongPollHandler) -> Result<HttpResponse, HttpError> {
+    let client_id = request.param("client_id").unwrap_or_default();
+    if !long_poll.notify(client_id, request.body.clone()) {
+        return Err(HttpError::NotFound);
+    }
+    Ok(HttpResponse::new(StatusCode::Accepted))
+}
+
+/// Turns a connection away with a 503 before any request is read.
+fn refuse_connection<W: Write>(conn: &mut W, server_name: Option<&str>) -> Result<(), Error> {
+    let mut headers = HashMap::new();
+    headers.insert(HeaderType::ContentLength, "0".to_owned());
+    headers.insert(HeaderType::Connection, "close".to_owned());
+    headers.insert(
+        HeaderType::RetryAfter,
+        UNAVAILABLE_RETRY_AFTER_SECS.to_string(),
+    );
+    if let Some(server_name) = server_name {
+        headers.insert(HeaderType::Server, server_name.to_owned());
+    }
+    let mut response = HttpResponse {
+        version: "HTTP/1.1".to_owned(),
+        status_code: StatusCode::ServiceUnavailable,
+        headers,
+        body: Vec::new().into(),
+    };
+    response.write_to(conn)
+}
+
+/// Combines `--host` and `--port` into the address to listen on. The host
+/// must be an IP address literal, v4 or v6.
+fn bind_address(host: &str, port: u16) -> Result<SocketAddr, Error> {
+    let ip: IpAddr = host
+        .parse()
+        .map_err(|_| format!("invalid host address: {}", host))?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Reads `--threads`. Waiting `/poll` requests may hold all but one
+/// worker, so fewer than [`MIN_THREADS`] would leave none for `/poll`.
+fn parse_threads(value: &str) -> Result<usize, Error> {
+    match value.parse() {
+        Ok(threads) if threads >= MIN_THREADS => Ok(threads),
+        _ => Err(format!(
+            "invalid --threads: {} (at least {} are needed)",
+            value, MIN_THREADS
+        )
+        .into()),
+    }
+}
+
+fn parse_encoding_list(list: &str) -> Result<Vec<EncodingType>, Error> {
+    let mut encodings = Vec::new();
+    for name in list.split(',').filter(|name| !name.trim().is_empty()) {
+        let encoding = EncodingType::from_str(name)
+            .map_err(|_| format!("unknown encoding: {}", name.trim()))?;
+        if !SUPPORTED_ENCODINGS.contains(&encoding) {
+            return Err(format!("{} encoding is not supported", encoding).into());
+        }
+        if !encodings.contains(&encoding) {
+            encodings.push(encoding);
+        }
+    }
+    Ok(encodings)
+}
+
+fn main() -> Result<(), Error> {
+    let mut config = Config::default();
+    let mut print_config = false;
+    let mut from_cli = HashSet::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if let Some(flag) = arg.strip_prefix("--") {
+            from_cli.insert(flag.to_owned());
+        }
+        if arg == "--print-config" {
+            print_config = true;
+        } else if arg == "--host" {
+            if let Some(host) = args.next() {
+                config.host = host;
+            }
+        } else if arg == "--port" {
+            if let Some(port) = args.next() {
+                config.port = port
+                    .parse()
+                    .map_err(|_| format!("invalid port: {}", port))?;
+            }
+        } else if arg == "--directory" {
+            if let Some(dir) = args.next() {
+                config.directory = PathBuf::from(dir);
+            }
+        } else if arg == "--allow-upload" {
+            config.allow_upload = true;
+        } else if arg == "--enable-encoding" {
+            if let Some(list) = args.next() {
+                config.enabled_encodings = parse_encoding_list(&list)?;
+            }
+        } else if arg == "--no-follow-symlinks" {
+            config.follow_symlinks = false;
+        } else if arg == "--deny-dotfiles" {
+            config.deny_dotfiles = true;
+        } else if arg == "--well-known-dir" {
+            if let Some(dir) = args.next() {
+                config.well_known_dir = Some(PathBuf::from(dir));
+            }
+        } else if arg == "--create-directory" {
+            config.create_directory = true;
+        } else if arg == "--default-mime" {
+            if let Some(mime) = args.next() {
+                config.default_mime = mime;
+            }
+        } else if arg == "--cors-expose-headers" {
+            if let Some(headers) = args.next() {
+                config.cors_expose_headers = Some(headers);
+            }
+        } else if arg == "--stream-threshold" {
+            if let Some(bytes) = args.next().and_then(|bytes| bytes.parse().ok()) {
+                config.stream_threshold = bytes;
+            }
+        } else if arg == "--strict-bodies" {
+            config.strict_bodies = true;
+        } else if arg == "--max-conn-per-ip" {
+            if let Some(limit) = args.next().and_then(|limit| limit.parse().ok()) {
+                config.max_conn_per_ip = Some(limit);
+            }
+        } else if arg == "--max-body-size" {
+            if let Some(bytes) = args.next().and_then(|bytes| bytes.parse().ok()) {
+                config.max_body_size = bytes;
+            }
+        } else if arg == "--max-request-line" {
+            if let Some(bytes) = args
+                .next()
+                .and_then(|bytes| bytes.parse().ok())
+                .filter(|&bytes| bytes > 0)
+            {
+                config.max_request_line = bytes;
+            }
+        } else if arg == "--max-header-line" {
+            if let Some(bytes) = args
+                .next()
+                .and_then(|bytes| bytes.parse().ok())
+                .filter(|&bytes| bytes > 0)
+            {
+                config.max_header_line = bytes;
+            }
+        } else if arg == "--max-header-bytes" {
+            if let Some(bytes) = args
+                .next()
+                .and_then(|bytes| bytes.parse().ok())
+                .filter(|&bytes| bytes > 0)
+            {
+                config.max_header_bytes = bytes;
+            }
+        } else if arg == "--max-header-count" {
+            if let Some(count) = args.next().and_then(|count| count.parse().ok()) {
+                config.max_header_count = count;
+            }
+        } else if arg == "--max-echo-length" {
+            if let Some(bytes) = args.next().and_then(|bytes| bytes.parse().ok()) {
+                config.max_echo_length = bytes;
+            }
+        } else if arg == "--echo-content-disposition" {
+            config.echo_disposition = true;
+        } else if arg == "--limits-path" {
+            if let Some(path) = args.next() {
+                config.limits_path = path;
+            }
+        } else if arg == "--log-slow-requests" {
+            if let Some(ms) = args.next().and_then(|ms| ms.parse().ok()) {
+                config.slow_request_threshold = Some(Duration::from_millis(ms));
+            }
+        } else if arg == "--server-name" {
+            if let Some(name) = args.next() {
+                // Control characters would let the value end the header
+                // line early and smuggle in headers of its own.
+                if name.chars().any(|c| c.is_control()) {
+                    return Err(format!("invalid --server-name: {:?}", name).into());
+                }
+                config.server_name = Some(name);
+            }
+        } else if arg == "--no-server-header" {
+            config.server_name = None;
+        } else if arg == "--keepalive-timeout" {
+            if let Some(secs) = args
+                .next()
+                .and_then(|secs| secs.parse().ok())
+                .filter(|&secs| secs > 0)
+            {
+                config.keepalive_timeout = Duration::from_secs(secs);
+            }
+        } else if arg == "--max-connection-duration" {
+            if let Some(secs) = args
+                .next()
+                .and_then(|secs| secs.parse().ok())
+                .filter(|&secs| secs > 0)
+            {
+                config.max_connection_duration = Some(Duration::from_secs(secs));
+            }
+        } else if arg == "--read-timeout-secs" {
+            if let Some(secs) = args
+                .next()
+                .and_then(|secs| secs.parse().ok())
+                .filter(|&secs| secs > 0)
+            {
+                config.read_timeout = Duration::from_secs(secs);
+            }
+        } else if arg == "--write-timeout-secs" {
+            if let Some(secs) = args
+                .next()
+                .and_then(|secs| secs.parse().ok())
+                .filter(|&secs| secs > 0)
+            {
+                config.write_timeout = Duration::from_secs(secs);
+            }
+        } else if arg == "--mount" {
+            if let Some(spec) = args.next() {
+                config.mounts.push(spec.parse()?);
+            }
+        } else if arg == "--queue-size" {
+            if let Some(size) = args
+                .next()
+                .and_then(|size| size.parse().ok())
+                .filter(|&size| size > 0)
+            {
+                config.queue_size = size;
+            }
+        } else if arg == "--queue-full" {
+            if let Some(policy) = args.next() {
+                config.queue_policy = policy
+                    .parse()
+                    .map_err(|_| format!("invalid --queue-full policy: {}", policy))?;
+            }
+        } else if arg == "--threads" {
+            if let Some(threads) = args.next() {
+                config.threads = parse_threads(&threads)?;
+            }
+        } else if arg == "--poll-timeout-secs" {
+            if let Some(secs) = args.next().and_then(|secs| secs.parse().ok()) {
+                config.poll_timeout = Duration::from_secs(secs);
+            }
+        }
+    }
+    if config.create_directory {
+        create_dir_all(&config.directory)?;
+    }
+    config.directory = config.directory.canonicalize().map_err(|err| {
+        format!(
+            "cannot serve directory {}: {}",
+            config.directory.display(),
+            err
+        )
+    })?;
+    if let Some(dir) = &mut config.well_known_dir {
+        *dir = dir
+            .canonicalize()
+            .map_err(|err| format!("cannot serve directory {}: {}", dir.display(), err))?;
+    }
+    for mount in &mut config.mounts {
+        if let Backend::Directory(root) = &mut mount.backend {
+            *root = root
+                .canonicalize()
+                .map_err(|err| format!("cannot serve directory {}: {}", root.display(), err))?;
+        }
+    }
+    if print_config {
+        println!("{}", config.to_json(&from_cli));
+        return Ok(());
+    }
+    let config = Arc::new(config);
+    // One worker always stays out of reach of /poll, so /notify can run.
+    let long_poll = Arc::new(LongPollHandler::new(config.threads.saturating_sub(1)));
+    let router = Arc::new(build_router(&config, &long_poll)?);
+
+    let limiter = Arc::new(ConnectionLimiter::new(config.max_conn_per_ip));
+
+    let addr = bind_address(&config.host, config.port)?;
+    let listener =
+        TcpListener::bind(addr).map_err(|err| format!("cannot listen on {}: {}", addr, err))?;
+    eprintln!(
+        "Listening on {} serving {} with {} threads",
+        addr,
+        config.directory.display(),
+        config.threads
+    );
+    let pool = ThreadPool::new(config.threads, config.queue_size);
+
+    for connection in listener.incoming() {
+        match connection {
+            Ok(mut conn) => {
+                let slot = if config.queue_policy == QueuePolicy::Reject && pool.is_full() {
+                    None
+                } else {
+                    PeerAddr::peer_addr(&conn).and_then(|peer| limiter.try_acquire(peer.ip()))
+                };
+                let Some(slot) = slot else {
+                    if let Err(err) = refuse_connection(&mut conn, config.server_name.as_deref()) {
+                        eprintln!("Failed to refuse connection: {}", err);
+                    }
+                    continue;
+                };
+
+                let config = config.clone();
+                let router = router.clone();
+                pool.execute(move || {
+                    let _slot = slot;
+                    if let Err(err) = connection_handler(conn, config, router) {
+                        eprintln!("Connection handler error: {}", err);
+                    }
+                });
+            }
+            Err(err) => {
+                eprintln!("Failed to accept connection: {}", err);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sends `raw` to a connection handler over a socket pair and returns
+    /// everything it wrote back. The client stops sending after `raw`, so
+    /// the handler runs until it has answered all of it.
+    fn connect(config: Config) -> (UnixStream, thread::JoinHandle<Result<(), Error>>) {
+        let config = Arc::new(config);
+        let long_poll = Arc::new(LongPollHandler::new(1));
+        let router = Arc::new(build_router(&config, &long_poll).unwrap());
+        let (client, server) = UnixStream::pair().unwrap();
+        let handler = thread::spawn(move || connection_handler(server, config, router));
+        (client, handler)
+    }
+
+    fn exchange(config: Config, raw: &[u8]) -> String {
+        let (mut client, handler) = connect(config);
+        client.write_all(raw).unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut output = Vec::new();
+        client.read_to_end(&mut output).unwrap();
+        let _ = handler.join().unwrap();
+        String::from_utf8_lossy(&output).into_owned()
+    }
+
+    #[test]
+    fn post_to_a_get_only_route_is_405() {
+        let output = exchange(
+            Config::default(),
+            b"POST /user-agent HTTP/1.1\r\nContent-Length: 0\r\n\r\n",
+        );
+        assert!(output.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        assert!(output.contains("\r\nAllow: GET, HEAD, OPTIONS\r\n"));
+    }
+
+    #[test]
+    fn lowercase_content_length_frames_the_body() {
+        let data = b"POST /notify/x HTTP/1.1\r\ncontent-length: 28\r\n\r\nGET /user-agent HTTP/1.1\r\n\r\n";
+        let mut reader = Cursor::new(&data[..]);
+        let request = HttpRequest::parse(&mut reader).unwrap();
+        assert_eq!(request.body, b"GET /user-agent HTTP/1.1\r\n\r\n");
+        assert!(matches!(
+            HttpRequest::parse(&mut reader),
+            Err(ParseError::ConnectionClosed)
+        ));
+    }
+
+    #[test]
+    fn chunked_request_body_is_decoded_through_a_cursor() {
+        let data = b"POST /notify/x HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\n\
+            X-Checksum: abc\r\nContent-Length: 99\r\n\r\n\
+            GET / HTTP/1.1\r\n\r\n";
+        let mut reader = Cursor::new(&data[..]);
+        let request = HttpRequest::parse(&mut reader).unwrap();
+        assert_eq!(request.body, b"hello world");
+        // Trailer fields are kept, except ones that would change framing.
+        assert_eq!(
+            request.headers[&HeaderType::Custom("x-checksum".to_owned())],
+            "abc"
+        );
+        assert!(!request.headers.contains_key(&HeaderType::ContentLength));
+
+        let next = HttpRequest::parse(&mut reader).unwrap();
+        assert_eq!(next.method, Method::Get);
+        assert_eq!(next.target.path, "/");
+    }
+
+    #[test]
+    fn unframed_transfer_codings_close_the_connection() {
+        // The body would otherwise be read as a second request.
+        let output = exchange(
+            Config::default(),
+            b"POST /notify/x HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n\
+              GET /user-agent HTTP/1.1\r\n\r\n",
+        );
+        assert!(output.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert_eq!(output.matches("HTTP/1.1 ").count(), 1);
+
+        let err = HttpRequest::from_bytes(
+            b"POST / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+        )
+        .unwrap_err();
+        assert!(matches!(err, ParseError::TransferEncodingInHttp10));
+        assert_eq!(err.status_code(), StatusCode::BadRequest);
+    }
+
+    #[test]
+    fn invalid_chunk_size_is_a_bad_request() {
+        for size in ["zz", "+5", "-5", "0x5", ""] {
+            let data = format!(
+                "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{}\r\nhello\r\n0\r\n\r\n",
+                size
+            );
+            let err = HttpRequest::from_bytes(data.as_bytes()).unwrap_err();
+            assert!(matches!(err, ParseError::InvalidChunkSize(_)), "{:?}", size);
+            assert_eq!(err.status_code(), StatusCode::BadRequest);
+        }
+    }
+
+    fn target(raw: &str) -> RequestTarget {
+        raw.parse().unwrap()
+    }
+
+    fn parse_head(request_line: &str) -> Result<HttpRequest, ParseError> {
+        let data = format!("{}\r\n\r\n", request_line);
+        HttpRequest::parse_head(&mut Cursor::new(data.as_bytes()), &Limits::default())
+    }
+
+    #[test]
+    fn parses_each_target_form() {
+        let origin = target("/search?q=rust&page=2");
+        assert_eq!(origin.form, TargetForm::Origin);
+        assert_eq!(origin.authority, None);
+        assert_eq!(origin.path, "/search");
+        assert_eq!(origin.query["q"], "rust");
+        assert_eq!(origin.query["page"], "2");
+
+        let absolute = target("http://example.com:8080?x=1");
+        assert_eq!(absolute.form, TargetForm::Absolute);
+        assert_eq!(absolute.authority.as_deref(), Some("example.com:8080"));
+        assert_eq!(absolute.path, "/");
+        assert_eq!(absolute.query["x"], "1");
+
+        let authority = target("example.com:443");
+        assert_eq!(authority.form, TargetForm::Authority);
+        assert_eq!(authority.authority.as_deref(), Some("example.com:443"));
+        assert_eq!(authority.path, "");
+
+        let asterisk = target("*");
+        assert_eq!(asterisk.form, TargetForm::Asterisk);
+        assert_eq!(asterisk.raw, "*");
+    }
+
+    #[test]
+    fn decodes_each_path_segment_and_refuses_nul() {
+        assert_eq!(target("/files/a%20b%3F.txt").path, "/files/a b?.txt");
+        assert_eq!(target("/files/caf%C3%A9").path, "/files/café");
+        assert_eq!(target("/a+b").path, "/a+b");
+        assert_eq!(target("/").segments, [""]);
+        assert_eq!(target("http://example.com?x=1").segments, [""]);
+        assert!(target("*").segments.is_empty());
+        // An encoded `/` is data within its segment, not a separator.
+        assert_eq!(target("/files/a%2Fb").segments, ["files", "a/b"]);
+        assert_eq!(target("/files/a%2fb/c").segments, ["files", "a/b", "c"]);
+        for raw in ["/files/a%00", "/files/%zz", "/files/%C3", "/search?q=%00"] {
+            assert!(
+                matches!(
+                    raw.parse::<RequestTarget>(),
+                    Err(ParseError::InvalidTarget(_))
+                ),
+                "{}",
+                raw
+            );
+        }
+    }
+
+    #[test]
+    fn encoded_slashes_stay_inside_their_segment() {
+        let output = exchange(Config::default(), b"GET /echo/a%2Fb HTTP/1.1\r\n\r\n");
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(output.ends_with("\r\n\r\na/b"));
+
+        // `a%2Fb` is one segment, so it must not reach the file `a/b`.
+        let dir = storage::tests::TempDir::new("encoded-slash");
+        std::fs::create_dir(dir.0.join("a")).unwrap();
+        let config = || Config {
+            allow_upload: true,
+            ..files_in(&dir, &[("a/b", b"nested")])
+        };
+        let output = exchange(config(), b"GET /files/a/b HTTP/1.1\r\n\r\n");
+        assert!(output.ends_with("\r\n\r\nnested"));
+        for request in [
+            &b"GET /files/a%2Fb HTTP/1.1\r\n\r\n"[..],
+            b"PUT /files/a%2Fb HTTP/1.1\r\nContent-Length: 1\r\n\r\nx",
+            b"DELETE /files/a%2Fb HTTP/1.1\r\n\r\n",
+        ] {
+            let output = exchange(config(), request);
+            assert!(
+                output.starts_with("HTTP/1.1 403 Forbidden\r\n"),
+                "{}",
+                output
+            );
+        }
+        assert_eq!(std::fs::read(dir.0.join("a/b")).unwrap(), b"nested");
+    }
+
+    #[test]
+    fn target_forms_are_tied_to_their_methods() {
+        assert!(parse_head("CONNECT example.com:443 HTTP/1.1").is_ok());
+        assert!(parse_head("OPTIONS * HTTP/1.1").is_ok());
+        for line in [
+            "GET foo HTTP/1.1",
+            "GET example.com:443 HTTP/1.1",
+            "GET * HTTP/1.1",
+            "CONNECT /path HTTP/1.1",
+            "CONNECT * HTTP/1.1",
+        ] {
+            assert!(
+                matches!(parse_head(line), Err(ParseError::InvalidTarget(_))),
+                "{}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn parses_responses_from_bytes() {
+        let response =
+            HttpResponse::from_bytes(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello").unwrap();
+        assert_eq!(response.status_code, StatusCode::Ok);
+        assert_eq!(body_bytes(response), b"hello");
+
+        let response = HttpResponse::from_bytes(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(body_bytes(response), b"abc");
+
+        let response = HttpResponse::from_bytes(b"HTTP/1.0 200 OK\r\n\r\nto the end").unwrap();
+        assert_eq!(response.version, "HTTP/1.0");
+        assert_eq!(body_bytes(response), b"to the end");
+
+        let response = HttpResponse::from_bytes(b"HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+        assert_eq!(body_bytes(response), b"");
+
+        let long = format!(
+            "HTTP/1.1 200 {}\r\n\r\n",
+            "O".repeat(DEFAULT_MAX_REQUEST_LINE)
+        );
+        let err = HttpResponse::from_bytes(long.as_bytes()).unwrap_err();
+        assert!(matches!(err, ParseError::StatusLineTooLong), "{:?}", err);
+        assert_eq!(err.to_string(), "status line too long");
+    }
+
+    #[test]
+    fn unknown_statuses_read_as_their_class() {
+        for (line, expected) in [
+            ("HTTP/1.1 299 Custom\r\n\r\n", StatusCode::Ok),
+            (
+                "HTTP/1.1 420 Enhance Your Calm\r\n\r\n",
+                StatusCode::BadRequest,
+            ),
+            ("HTTP/1.1 599\r\n\r\n", StatusCode::InternalServerError),
+            ("HTTP/1.1 418 I'm a teapot\r\n\r\n", StatusCode::ImATeapot),
+        ] {
+            let response = HttpResponse::from_bytes(line.as_bytes()).unwrap();
+            assert_eq!(response.status_code, expected, "{}", line);
+        }
+        for code in ["099", "600", "20", "2000", "+20", "abc", ""] {
+            let line = format!("HTTP/1.1 {} Whatever\r\n\r\n", code);
+            assert!(
+                matches!(
+                    HttpResponse::from_bytes(line.as_bytes()),
+                    Err(ParseError::InvalidStatus(_))
+                ),
+                "{}",
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn truncated_messages_are_errors() {
+        let request = b"POST /notify/x HTTP/1.1\r\nContent-Length: 10\r\n\r\nhello";
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nhello";
+        for end in 1..request.len() {
+            assert!(
+                HttpRequest::parse(&mut Cursor::new(&request[..end])).is_err(),
+                "{:?}",
+                String::from_utf8_lossy(&request[..end])
+            );
+        }
+        for end in 0..response.len() {
+            assert!(HttpResponse::from_bytes(&response[..end]).is_err());
+        }
+        assert!(matches!(
+            HttpRequest::parse(&mut Cursor::new(&b""[..])),
+            Err(ParseError::ConnectionClosed)
+        ));
+    }
+
+    #[test]
+    fn arbitrary_bytes_never_panic_the_parsers() {
+        let valid = b"POST /files/a?x=1 HTTP/1.1\r\nHost: h\r\nTransfer-Encoding: chunked\r\n\r\n\
+            4\r\nbody\r\n0\r\nX-T: 1\r\n\r\n";
+        // A fixed xorshift sequence keeps failures reproducible.
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..5000 {
+            let mut data = valid.to_vec();
+            for _ in 0..next() % 4 + 1 {
+                let index = (next() % data.len() as u64) as usize;
+                data[index] = next() as u8;
+            }
+            let _ = HttpRequest::from_bytes(&data);
+            let _ = HttpResponse::from_bytes(&data);
+        }
+    }
+
+    fn request_with(header: &str) -> HttpRequest {
+        let head = format!("GET /echo/hi HTTP/1.1\r\n{}\r\n\r\n", header);
+        let mut request = HttpRequest::from_bytes(head.as_bytes()).unwrap();
+        request.params.insert("message".to_owned(), "hi".to_owned());
+        request
+    }
+
+    #[test]
+    fn selects_a_single_encoding_by_q_value() {
+        use EncodingType::{Deflate, Gzip};
+        let enabled = SUPPORTED_ENCODINGS;
+        for (accept, expected) in [
+            ("gzip;q=0, deflate", Some(Deflate)),
+            ("gzip, deflate", Some(Gzip)),
+            ("gzip;q=0.5, deflate;q=0.8", Some(Deflate)),
+            ("DEFLATE", Some(Deflate)),
+            ("*", Some(Gzip)),
+            ("*;q=0.5, gzip;q=0", Some(Deflate)),
+            ("gzip;q=0.5, identity", None),
+            ("br, zstd", None),
+            ("gzip;q=nonsense", None),
+            ("*;q=0", None),
+            ("", None),
+        ] {
+            assert_eq!(select_encoding(accept, &enabled), expected, "{:?}", accept);
+        }
+        // A preferred coding that isn't enabled loses to one that is.
+        assert_eq!(select_encoding("br;q=1, gzip;q=0.5", &[Gzip]), Some(Gzip));
+    }
+
+    #[test]
+    fn refusing_identity_without_an_alternative_is_406() {
+        let config = Config::default();
+        for accept in ["*;q=0", "identity;q=0", "br, identity;q=0"] {
+            let request = request_with(&format!("Accept-Encoding: {}", accept));
+            assert!(
+                matches!(
+                    negotiate_encoding(&request, &config.enabled_encodings),
+                    Err(HttpError::NotAcceptable)
+                ),
+                "{:?}",
+                accept
+            );
+        }
+
+        // An empty header, or none at all, means identity.
+        let empty = request_with("Accept-Encoding: ");
+        assert_eq!(
+            negotiate_encoding(&empty, &config.enabled_encodings).unwrap(),
+            None
+        );
+        let response = echo(&empty, &config).unwrap();
+        assert!(!response.headers.contains_key(&HeaderType::ContentEncoding));
+        assert_eq!(body_bytes(response), b"hi");
+        let absent = request_with("Accept: */*");
+        assert_eq!(
+            negotiate_encoding(&absent, &config.enabled_encodings).unwrap(),
+            None
+        );
+        // `*;q=0` still allows an identity that is listed explicitly.
+        let listed = request_with("Accept-Encoding: *;q=0, identity");
+        assert_eq!(
+            negotiate_encoding(&listed, &config.enabled_encodings).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn encoded_bodies_decompress_to_the_original() {
+        use flate2::read::{GzDecoder, ZlibDecoder};
+
+        let text = "The quick brown fox jumps over the lazy dog. ".repeat(20);
+        let deflated = EncodingType::Deflate.encode(text.as_bytes()).unwrap();
+        assert!(deflated.len() < text.len());
+        let mut decoded = String::new();
+        ZlibDecoder::new(&deflated[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, text);
+
+        let gzipped = EncodingType::Gzip.encode(text.as_bytes()).unwrap();
+        let mut decoded = String::new();
+        GzDecoder::new(&gzipped[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, text);
+
+        assert!(EncodingType::Brotli.encode(b"x").is_err());
+    }
+
+    #[test]
+    fn list_values_are_merged_without_duplicates() {
+        let mut response = HttpResponse::new(StatusCode::Ok);
+        response.append_list_value(HeaderType::Vary, "Accept-Encoding");
+        response.append_list_value(HeaderType::Vary, "Origin");
+        response.append_list_value(HeaderType::Vary, "accept-encoding");
+        assert_eq!(
+            response.headers[&HeaderType::Vary],
+            "Accept-Encoding, Origin"
+        );
+
+        // Existing values are normalized, empty items dropped.
+        response
+            .headers
+            .insert(HeaderType::Vary, " Accept ,, Origin".to_owned());
+        response.append_list_value(HeaderType::Vary, "Origin");
+        assert_eq!(response.headers[&HeaderType::Vary], "Accept, Origin");
+
+        // `*` already covers any other field.
+        response.headers.insert(HeaderType::Vary, "*".to_owned());
+        response.append_list_value(HeaderType::Vary, "Origin");
+        assert_eq!(response.headers[&HeaderType::Vary], "*");
+    }
+
+    #[test]
+    fn close_beats_keep_alive() {
+        let mut response = HttpResponse::new(StatusCode::Ok);
+        response.append_list_value(HeaderType::Connection, "keep-alive");
+        response.append_list_value(HeaderType::Connection, "close");
+        assert_eq!(response.headers[&HeaderType::Connection], "close");
+        response.append_list_value(HeaderType::Connection, "Keep-Alive");
+        assert_eq!(response.headers[&HeaderType::Connection], "close");
+
+        let mut response = HttpResponse::new(StatusCode::Ok);
+        response.append_list_value(HeaderType::Connection, "Upgrade");
+        response.append_list_value(HeaderType::Connection, "close");
+        assert_eq!(response.headers[&HeaderType::Connection], "Upgrade, close");
+    }
+
+    #[test]
+    fn compression_and_cors_share_one_vary() {
+        let config = Config {
+            cors_expose_headers: Some("X-Request-Id".to_owned()),
+            ..Config::default()
+        };
+        let output = exchange(
+            config,
+            b"GET /echo/hi HTTP/1.1\r\nAccept-Encoding: gzip\r\nOrigin: http://a.example\r\n\r\n",
+        );
+        let vary: Vec<&str> = output
+            .lines()
+            .filter_map(|line| line.strip_prefix("Vary: "))
+            .collect();
+        assert_eq!(vary, ["Accept-Encoding, Origin"]);
+    }
+
+    #[test]
+    fn tar_mounts_serve_ranges_and_refuse_writes() {
+        use storage::tests::{tar_archive, TempDir};
+
+        let dir = TempDir::new("tar-mount");
+        let archive = dir.0.join("site.tar");
+        std::fs::write(
+            &archive,
+            tar_archive(&[("docs/guide.txt", b'0', b"0123456789")]),
+        )
+        .unwrap();
+        let config = || Config {
+            mounts: vec![format!("/archive=tar:{}", archive.display())
+                .parse()
+                .unwrap()],
+            ..Config::default()
+        };
+
+        let output = exchange(
+            config(),
+            b"GET /archive/docs/guide.txt HTTP/1.1\r\nRange: bytes=2-4\r\n\r\n",
+        );
+        assert!(output.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(output.contains("\r\nContent-Range: bytes 2-4/10\r\n"));
+        assert!(output.ends_with("\r\n\r\n234"));
+
+        let output = exchange(
+            config(),
+            b"PUT /archive/docs/guide.txt HTTP/1.1\r\nContent-Length: 1\r\n\r\nx",
+        );
+        assert!(output.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        assert!(output.contains("\r\nAllow: GET, HEAD, OPTIONS\r\n"));
+        assert!("/archive=tar:x.tar,rw".parse::<Mount>().is_err());
+    }
+
+    #[test]
+    fn pool_runs_more_jobs_than_workers() {
+        let done = Arc::new(AtomicUsize::new(0));
+        let pool = ThreadPool::new(2, 4);
+        for _ in 0..50 {
+            let done = done.clone();
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(1));
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        // Dropping the pool waits for the queue to drain.
+        drop(pool);
+        assert_eq!(done.load(Ordering::SeqCst), 50);
+    }
+
+    #[test]
+    fn pool_survives_a_panicking_job() {
+        let done = Arc::new(AtomicUsize::new(0));
+        let pool = ThreadPool::new(1, 1);
+        pool.execute(|| panic!("job failed"));
+        let counter = done.clone();
+        pool.execute(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        drop(pool);
+        assert_eq!(done.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn long_polls_beyond_the_waiter_limit_are_refused() {
+        let long_poll = Arc::new(LongPollHandler::new(1));
+        let waiter = {
+            let long_poll = long_poll.clone();
+            thread::spawn(move || long_poll.wait("a", Duration::from_secs(10)))
+        };
+        while long_poll.waiting.load(Ordering::SeqCst) == 0 {
+            thread::yield_now();
+        }
+        assert!(matches!(
+            long_poll.wait("b", Duration::from_secs(10)),
+            Err(HttpError::Unavailable)
+        ));
+        while !long_poll.notify("a", b"hi".to_vec()) {
+            thread::yield_now();
+        }
+        assert_eq!(waiter.join().unwrap().unwrap(), Some(b"hi".to_vec()));
+        // The slot is free again.
+        assert_eq!(long_poll.wait("b", Duration::ZERO).unwrap(), None);
+    }
+
+    #[test]
+    fn a_single_thread_is_refused() {
+        // One worker would leave /poll no waiter slots, and zero would
+        // serve nothing at all.
+        for threads in ["0", "1", "-1", "many"] {
+            assert!(parse_threads(threads).is_err(), "{}", threads);
+        }
+        assert_eq!(parse_threads("2").unwrap(), 2);
+        assert!(Config::default().threads >= MIN_THREADS);
+    }
+
+    #[test]
+    fn hundreds_of_concurrent_connections_all_complete() {
+        const CONNECTIONS: usize = 300;
+        let config = Arc::new(Config::default());
+        let long_poll = Arc::new(LongPollHandler::new(1));
+        let router = Arc::new(build_router(&config, &long_poll).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let pool = ThreadPool::new(8, 16);
+            for conn in listener.incoming().take(CONNECTIONS) {
+                let (config, router) = (config.clone(), router.clone());
+                let conn = conn.unwrap();
+                pool.execute(move || {
+                    connection_handler(conn, config, router).unwrap();
+                });
+            }
+        });
+
+        let clients: Vec<_> = (0..CONNECTIONS)
+            .map(|i| {
+                thread::spawn(move || {
+                    let mut conn = TcpStream::connect(addr).unwrap();
+                    write!(
+                        conn,
+                        "GET /echo/{} HTTP/1.1\r\nConnection: close\r\n\r\n",
+                        i
+                    )
+                    .unwrap();
+                    let mut response = String::new();
+                    conn.read_to_string(&mut response).unwrap();
+                    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+                    assert!(response.ends_with(&format!("\r\n\r\n{}", i)));
+                })
+            })
+            .collect();
+        for client in clients {
+            client.join().unwrap();
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn user_agent_renders_per_accept() {
+        let fetch = |accept: &str| {
+            let head = format!(
+                "GET /user-agent HTTP/1.1\r\nUser-Agent: curl/8 <\"x\">\r\n{}\r\n\r\n",
+                accept
+            );
+            let response = user_agent(&HttpRequest::from_bytes(head.as_bytes()).unwrap()).unwrap();
+            assert_eq!(response.headers[&HeaderType::Vary], "Accept");
+            let content_type = response.headers[&HeaderType::ContentType].clone();
+            (
+                content_type,
+                String::from_utf8(body_bytes(response)).unwrap(),
+            )
+        };
+
+        let plain = ("text/plain".to_owned(), "curl/8 <\"x\">".to_owned());
+        assert_eq!(fetch(""), plain);
+        assert_eq!(fetch("Accept: text/plain"), plain);
+        let (content_type, html) = fetch("Accept: text/html");
+        assert_eq!(content_type, "text/html");
+        assert!(html.contains("<p>curl/8 &lt;&quot;x&quot;&gt;</p>"));
+        assert_eq!(
+            fetch("Accept: application/json"),
+            (
+                "application/json".to_owned(),
+                r#"{"user_agent": "curl/8 <\"x\">"}"#.to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn reflected_content_is_marked_nosniff() {
+        let output = exchange(
+            Config::default(),
+            b"GET /echo/%3Cscript%3Ealert(1)%3Cb%3E HTTP/1.1\r\n\r\n",
+        );
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(output.contains("\r\nContent-Type: text/plain\r\n"));
+        assert!(output.contains("\r\nX-Content-Type-Options: nosniff\r\n"));
+        assert!(!output.contains("Content-Disposition"));
+        assert!(output.ends_with("\r\n\r\n<script>alert(1)<b>"));
+
+        let config = Config {
+            echo_disposition: true,
+            max_echo_length: 8,
+            ..Config::default()
+        };
+        let output = exchange(
+            config,
+            b"GET /echo/short HTTP/1.1\r\n\r\nGET /echo/much-too-long HTTP/1.1\r\n\r\n",
+        );
+        assert!(output.contains("\r\nContent-Disposition: inline; filename=\"echo.txt\"\r\n"));
+        assert!(output.contains("HTTP/1.1 414 URI Too Long\r\n"));
+
+        // Parse errors quote the target back, so they get the same treatment.
+        let output = exchange(Config::default(), b"GET <script> HTTP/1.1\r\n\r\n");
+        assert!(output.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(output.contains("\r\nContent-Type: text/plain\r\n"));
+        assert!(output.contains("\r\nX-Content-Type-Options: nosniff\r\n"));
+    }
+
+    #[test]
+    fn pipelined_requests_share_one_connection() {
+        let output = exchange(
+            Config::default(),
+            b"GET /echo/one HTTP/1.1\r\n\r\nGET /echo/two HTTP/1.1\r\n\r\n",
+        );
+        let responses: Vec<&str> = output.split("HTTP/1.1 200 OK\r\n").skip(1).collect();
+        assert_eq!(responses.len(), 2);
+        assert!(responses[0]
+            .lines()
+            .any(|line| line == "Connection: keep-alive"));
+        assert!(responses[0].ends_with("\r\n\r\none"));
+        assert!(responses[1].ends_with("\r\n\r\ntwo"));
+
+        // `Connection: close` ends the exchange after the first response.
+        let output = exchange(
+            Config::default(),
+            b"GET /echo/one HTTP/1.1\r\nConnection: close\r\n\r\nGET /echo/two HTTP/1.1\r\n\r\n",
+        );
+        assert_eq!(output.matches("HTTP/1.1 200 OK").count(), 1);
+        assert!(output.contains("\r\nConnection: close\r\n"));
+    }
+
+    #[test]
+    fn head_user_agent_sends_the_length_without_a_body() {
+        let output = exchange(
+            Config::default(),
+            b"HEAD /user-agent HTTP/1.1\r\nUser-Agent: foobar/1.2.3\r\n\r\n",
+        );
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(output.contains("\r\nContent-Length: 12\r\n"));
+        assert!(output.ends_with("\r\n\r\n"));
+    }
+
+    fn files_in(dir: &storage::tests::TempDir, files: &[(&str, &[u8])]) -> Config {
+        for (name, contents) in files {
+            std::fs::write(dir.0.join(name), contents).unwrap();
+        }
+        Config {
+            directory: dir.0.clone(),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn head_on_a_file_keeps_its_headers() {
+        let dir = storage::tests::TempDir::new("head");
+        let config = files_in(&dir, &[("foo.txt", b"0123456789")]);
+        let output = exchange(config, b"HEAD /files/foo.txt HTTP/1.1\r\n\r\n");
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(output.contains("\r\nContent-Length: 10\r\n"));
+        assert!(output.contains("\r\nContent-Type: text/plain\r\n"));
+        assert!(output.contains("\r\nETag: "));
+        assert!(output.ends_with("\r\n\r\n"));
+
+        let output = exchange(Config::default(), b"HEAD /echo/foo HTTP/1.1\r\n\r\n");
+        assert!(output.contains("\r\nContent-Length: 3\r\n"));
+        assert!(output.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn a_vanished_root_answers_503() {
+        let dir = storage::tests::TempDir::new("vanished");
+        let root = dir.0.join("root");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(root.join("a.txt"), b"a").unwrap();
+        let config = Config {
+            directory: root.clone(),
+            ..Config::default()
+        };
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let output = exchange(config, b"GET /files/a.txt HTTP/1.1\r\n\r\n");
+        assert!(output.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert_eq!(
+            header(&output, "Retry-After"),
+            Some(UNAVAILABLE_RETRY_AFTER_SECS.to_string().as_str())
+        );
+    }
+
+    #[test]
+    fn connection_limiter_counts_per_ip() {
+        let limiter = Arc::new(ConnectionLimiter::new(Some(2)));
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let first = limiter.try_acquire(a).unwrap();
+        let second = limiter.try_acquire(a).unwrap();
+        assert!(limiter.try_acquire(a).is_none());
+        let other = limiter.try_acquire(b).unwrap();
+
+        drop(first);
+        let third = limiter.try_acquire(a).unwrap();
+        drop((second, third, other));
+        assert!(limiter.counts.lock().unwrap().is_empty());
+
+        // A refused client still learns which server turned it away.
+        let mut refusal = Vec::new();
+        refuse_connection(&mut refusal, Some("test-server")).unwrap();
+        let refusal = String::from_utf8(refusal).unwrap();
+        assert!(refusal.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert_eq!(header(&refusal, "Server"), Some("test-server"));
+        assert_eq!(
+            header(&refusal, "Retry-After"),
+            Some(UNAVAILABLE_RETRY_AFTER_SECS.to_string().as_str())
+        );
+
+        let unlimited = Arc::new(ConnectionLimiter::new(None));
+        let slots: Vec<_> = (0..100)
+            .map(|_| unlimited.try_acquire(a).unwrap())
+            .collect();
+        assert_eq!(unlimited.counts.lock().unwrap()[&a], slots.len());
+    }
+
+    #[test]
+    fn server_limits_reports_the_config() {
+        let config = Config {
+            max_body_size: 1234,
+            max_conn_per_ip: Some(3),
+            threads: 5,
+            enabled_encodings: vec![EncodingType::Gzip],
+            limits_path: "/limits".to_owned(),
+            ..Config::default()
+        };
+        let output = exchange(config, b"GET /limits HTTP/1.1\r\n\r\n");
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert_eq!(header(&output, "Content-Type"), Some("application/json"));
+        let body = output.split("\r\n\r\n").nth(1).unwrap();
+        assert!(body.contains("\"max_body_size\": 1234,"));
+        assert!(body.contains("\"max_pollers\": 4,"));
+        assert!(body.contains("\"max_conn_per_ip\": 3,"));
+        assert!(body.ends_with("\"encodings\": [\"gzip\"]}"));
+
+        let output = exchange(
+            Config::default(),
+            b"GET /.well-known/server-limits HTTP/1.1\r\n\r\n",
+        );
+        assert!(output.contains("\"max_conn_per_ip\": null,"));
+    }
+
+    #[test]
+    fn idle_keep_alive_connections_close_after_the_timeout() {
+        let config = Config {
+            keepalive_timeout: Duration::from_millis(100),
+            read_timeout: Duration::from_secs(10),
+            ..Config::default()
+        };
+        let (mut client, handler) = connect(config);
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        // Without the keep-alive timeout this would wait out the 10s read
+        // timeout and fail the client's 5s one.
+        let mut output = String::new();
+        client.read_to_string(&mut output).unwrap();
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert_eq!(header(&output, "Connection"), Some("keep-alive"));
+        assert!(handler.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn connections_close_after_their_maximum_duration() {
+        let config = Config {
+            max_connection_duration: Some(Duration::from_millis(200)),
+            ..Config::default()
+        };
+        let (mut client, handler) = connect(config);
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(300));
+        // The first request past the deadline is still answered; the one
+        // pipelined behind it never is.
+        client
+            .write_all(b"GET / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n")
+            .unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut output = String::new();
+        client.read_to_string(&mut output).unwrap();
+        assert!(handler.join().unwrap().is_ok());
+
+        let connection: Vec<_> = output
+            .lines()
+            .filter_map(|line| line.strip_prefix("Connection: "))
+            .collect();
+        assert_eq!(connection, ["keep-alive", "close"]);
+    }
+
+    fn header<'a>(output: &'a str, name: &str) -> Option<&'a str> {
+        output
+            .split("\r\n\r\n")
+            .next()
+            .unwrap()
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(": "))
+    }
+
+    #[test]
+    fn server_header_is_configurable() {
+        let request = b"GET / HTTP/1.1\r\n\r\n";
+        let output = exchange(Config::default(), request);
+        assert_eq!(
+            header(&output, "Server"),
+            Some(format!("vinhtc27-http/{}", env!("CARGO_PKG_VERSION")).as_str())
+        );
+
+        let config = Config {
+            server_name: Some("edge".to_owned()),
+            ..Config::default()
+        };
+        assert_eq!(header(&exchange(config, request), "Server"), Some("edge"));
+
+        let config = Config {
+            server_name: None,
+            ..Config::default()
+        };
+        assert_eq!(header(&exchange(config, request), "Server"), None);
+
+        // Error responses written before routing carry it too.
+        let output = exchange(Config::default(), b"BREW / HTTP/1.1\r\n\r\n");
+        assert!(output.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(header(&output, "Server").is_some());
+    }
+
+    #[test]
+    fn every_response_carries_a_current_date() {
+        let output = exchange(
+            Config::default(),
+            b"GET / HTTP/1.1\r\n\r\nGET /missing HTTP/1.1\r\n\r\nBREW / HTTP/1.1\r\n\r\n",
+        );
+        let dates: Vec<&str> = output
+            .lines()
+            .filter_map(|line| line.strip_prefix("Date: "))
+            .collect();
+        assert_eq!(dates.len(), 3);
+        for date in dates {
+            let sent = parse_http_date(date).unwrap();
+            let age = SystemTime::now().duration_since(sent).unwrap();
+            assert!(age < Duration::from_secs(60), "{}", date);
+        }
+    }
+
+    #[test]
+    fn cors_expose_headers_on_cross_origin_requests() {
+        let config = || Config {
+            cors_expose_headers: Some("X-Request-Id, ETag".to_owned()),
+            ..Config::default()
+        };
+        let expose =
+            |output: &str| header(output, "Access-Control-Expose-Headers").map(str::to_owned);
+
+        let output = exchange(
+            config(),
+            b"GET /echo/hi HTTP/1.1\r\nOrigin: http://a.example\r\n\r\n",
+        );
+        assert_eq!(expose(&output).as_deref(), Some("X-Request-Id, ETag"));
+        assert_eq!(header(&output, "Vary"), Some("Accept-Encoding, Origin"));
+
+        let output = exchange(config(), b"GET /echo/hi HTTP/1.1\r\n\r\n");
+        assert_eq!(expose(&output), None);
+        let output = exchange(
+            config(),
+            b"OPTIONS /echo/hi HTTP/1.1\r\nOrigin: http://a.example\r\n\
+              Access-Control-Request-Method: GET\r\n\r\n",
+        );
+        assert_eq!(expose(&output), None);
+        let output = exchange(
+            Config::default(),
+            b"GET /echo/hi HTTP/1.1\r\nOrigin: http://a.example\r\n\r\n",
+        );
+        assert_eq!(expose(&output), None);
+    }
+
+    #[test]
+    fn bodies_on_get_are_consumed_or_refused() {
+        let request = b"GET /echo/one HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\
+            GET /echo/two HTTP/1.1\r\n\r\n";
+        let output = exchange(Config::default(), request);
+        assert_eq!(output.matches("HTTP/1.1 200 OK\r\n").count(), 2);
+        assert!(output.ends_with("\r\n\r\ntwo"));
+
+        let strict = Config {
+            strict_bodies: true,
+            ..Config::default()
+        };
+        let output = exchange(strict, request);
+        assert!(output.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(output.contains("request body not allowed on GET"));
+        // The body was still framed, so the next request is answered.
+        assert!(output.ends_with("\r\n\r\ntwo"));
+    }
+
+    #[test]
+    fn uploads_need_allow_upload() {
+        let dir = storage::tests::TempDir::new("upload-gate");
+        let request = b"POST /files/new.txt HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello";
+
+        let output = exchange(files_in(&dir, &[]), request);
+        assert!(output.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        assert!(!dir.0.join("new.txt").exists());
+
+        let config = Config {
+            allow_upload: true,
+            ..files_in(&dir, &[])
+        };
+        let output = exchange(config, request);
+        assert!(output.starts_with("HTTP/1.1 201 Created\r\n"));
+        assert_eq!(std::fs::read(dir.0.join("new.txt")).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn oversized_uploads_are_refused_before_writing() {
+        let dir = storage::tests::TempDir::new("upload-limit");
+        let config = Config {
+            allow_upload: true,
+            max_body_size: 4,
+            ..files_in(&dir, &[])
+        };
+        let output = exchange(
+            config,
+            b"POST /files/big.txt HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello",
+        );
+        assert!(output.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+        assert!(output.contains("\r\nConnection: close\r\n"));
+        assert_eq!(std::fs::read_dir(&dir.0).unwrap().count(), 0);
+
+        // Refused on the declared length alone, without waiting for a body.
+        let output = exchange(
+            Config::default(),
+            b"POST /files/big.txt HTTP/1.1\r\nContent-Length: 99999999999\r\n\r\n",
+        );
+        assert!(output.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+        let output = exchange(
+            Config::default(),
+            b"POST /files/big.txt HTTP/1.1\r\nContent-Length: lots\r\n\r\n",
+        );
+        assert!(output.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[test]
+    fn traversal_payloads_never_leave_the_root() {
+        let dir = storage::tests::TempDir::new("traversal");
+        std::fs::create_dir(dir.0.join("root")).unwrap();
+        std::fs::write(dir.0.join("secret.txt"), b"s3cr3t-contents").unwrap();
+        let config = || Config {
+            directory: dir.0.join("root"),
+            allow_upload: true,
+            ..Config::default()
+        };
+        for target in [
+            "/files/../secret.txt",
+            "/files/a/../../secret.txt",
+            "/files/%2e%2e/secret.txt",
+            "/files/%2E%2E/%2e%2e/etc/passwd",
+            "/files//etc/passwd",
+            "/files/..%2Fsecret.txt",
+        ] {
+            for method in ["GET", "POST"] {
+                let request = format!(
+                    "{} {} HTTP/1.1\r\nContent-Length: 1\r\n\r\nx",
+                    method, target
+                );
+                let output = exchange(config(), request.as_bytes());
+                let status = output.split(' ').nth(1).unwrap();
+                assert!(
+                    ["400", "403", "404"].contains(&status),
+                    "{} {} -> {}",
+                    method,
+                    target,
+                    status
+                );
+                assert!(!output.contains("s3cr3t-contents"), "{} {}", method, target);
+            }
+        }
+        assert_eq!(
+            std::fs::read(dir.0.join("secret.txt")).unwrap(),
+            b"s3cr3t-contents"
+        );
+        assert_eq!(std::fs::read_dir(dir.0.join("root")).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn well_known_is_served_while_dotfiles_stay_hidden() {
+        let dir = storage::tests::TempDir::new("well-known");
+        let well_known = dir.0.join("well-known");
+        std::fs::create_dir(&well_known).unwrap();
+        std::fs::write(well_known.join("security.txt"), b"Contact: sec@example.com").unwrap();
+        let config = || Config {
+            deny_dotfiles: true,
+            well_known_dir: Some(well_known.clone()),
+            ..files_in(&dir, &[(".env", b"TOKEN=1"), ("public.txt", b"hi")])
+        };
+
+        let output = exchange(config(), b"GET /.well-known/security.txt HTTP/1.1\r\n\r\n");
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(output.ends_with("Contact: sec@example.com"));
+
+        let output = exchange(config(), b"GET /files/.env HTTP/1.1\r\n\r\n");
+        assert!(output.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let output = exchange(config(), b"GET /files/public.txt HTTP/1.1\r\n\r\n");
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn parses_single_byte_ranges() {
+        use ByteRange::{Full, Partial, Unsatisfiable};
+        for (range, expected) in [
+            ("bytes=0-499", Partial(0, 499)),
+            ("bytes=100-", Partial(100, 999)),
+            ("bytes=-500", Partial(500, 999)),
+            ("bytes=-5000", Partial(0, 999)),
+            ("bytes=900-5000", Partial(900, 999)),
+            ("BYTES = 0-0", Partial(0, 0)),
+            ("bytes=1000-", Unsatisfiable),
+            ("bytes=-0", Unsatisfiable),
+            ("bytes=5-4", Full),
+            ("bytes=0-1,5-6", Full),
+            ("items=0-1", Full),
+            ("bytes=+1-2", Full),
+            ("bytes=a-b", Full),
+            ("bytes=", Full),
+        ] {
+            assert_eq!(ByteRange::parse(range, 1000), expected, "{}", range);
+        }
+        assert_eq!(ByteRange::parse("bytes=-5", 0), Unsatisfiable);
+    }
+
+    #[test]
+    fn range_requests_on_files() {
+        let storage = memory_files(&[("a.txt", b"0123456789")]);
+        let config = Config::default();
+        let fetch = |range: &str| {
+            let head = format!("GET /files/a.txt HTTP/1.1\r\nRange: {}\r\n\r\n", range);
+            let mut response = get(&storage, &config, &head);
+            let mut body = Vec::new();
+            if let Body::Reader { reader, len } = &mut response.body {
+                reader.take(*len).read_to_end(&mut body).unwrap();
+            }
+            (response, body)
+        };
+
+        for (range, content_range, expected) in [
+            ("bytes=2-4", "bytes 2-4/10", &b"234"[..]),
+            ("bytes=7-", "bytes 7-9/10", b"789"),
+            ("bytes=-2", "bytes 8-9/10", b"89"),
+        ] {
+            let (response, body) = fetch(range);
+            assert_eq!(response.status_code, StatusCode::PartialContent);
+            assert_eq!(response.headers[&HeaderType::ContentRange], content_range);
+            assert_eq!(body, expected);
+            response.check_content_range().unwrap();
+        }
+
+        let (response, _) = fetch("bytes=10-");
+        assert_eq!(response.status_code, StatusCode::RangeNotSatisfiable);
+        assert_eq!(response.headers[&HeaderType::ContentRange], "bytes */10");
+
+        let (response, _) = fetch("bytes=0-1,4-5");
+        assert_eq!(response.status_code, StatusCode::Ok);
+        assert_eq!(body_bytes(response), b"0123456789");
+    }
+
+    #[test]
+    fn inconsistent_content_range_is_caught() {
+        let partial = |content_range: Option<&str>, body: Body| {
+            let mut response = HttpResponse::new(StatusCode::PartialContent);
+            if let Some(content_range) = content_range {
+                response
+                    .headers
+                    .insert(HeaderType::ContentRange, content_range.to_owned());
+            }
+            response.body = body;
+            response
+        };
+
+        assert!(partial(Some("bytes 0-4/10"), b"01234".to_vec().into())
+            .check_content_range()
+            .is_ok());
+        for (content_range, body) in [
+            (Some("bytes 0-4/10"), b"0123".to_vec()),
+            (Some("bytes 4-0/10"), b"0".to_vec()),
+            (Some("bytes */10"), Vec::new()),
+            (None, b"0".to_vec()),
+        ] {
+            assert!(
+                partial(content_range, body.into())
+                    .check_content_range()
+                    .is_err(),
+                "{:?}",
+                content_range
+            );
+        }
+        let chunked = Body::Chunked(Box::new(Cursor::new(b"0".to_vec())));
+        assert!(partial(Some("bytes 0-0/1"), chunked)
+            .check_content_range()
+            .is_err());
+        // Other statuses aren't checked.
+        assert!(HttpResponse::new(StatusCode::Ok)
+            .check_content_range()
+            .is_ok());
+
+        // The guard turns the broken response into a 500.
+        let mut router = Router::new();
+        router.get("/broken", |_| {
+            let mut response = HttpResponse::new(StatusCode::PartialContent);
+            response
+                .headers
+                .insert(HeaderType::ContentRange, "bytes 0-9/10".to_owned());
+            response.body = b"short".to_vec().into();
+            Ok(response)
+        });
+        let request = HttpRequest::from_bytes(b"GET /broken HTTP/1.1\r\n\r\n").unwrap();
+        let response = handle_request(request, &Config::default(), &router);
+        assert_eq!(response.status_code, StatusCode::InternalServerError);
+    }
+
+    #[test]
+    fn reads_query_parameters() {
+        let request =
+            HttpRequest::from_bytes(b"GET /search?q=rust&page=2 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(request.target.path, "/search");
+        assert_eq!(request.query_param("q"), Some("rust"));
+        assert_eq!(request.query_param("page"), Some("2"));
+        assert_eq!(request.query_param("missing"), None);
+
+        let request = HttpRequest::from_bytes(
+            b"GET /search?q=a+b%26c&flag&q2=%3D&q2=last&= HTTP/1.1\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(request.query_param("q"), Some("a b&c"));
+        assert_eq!(request.query_param("flag"), Some(""));
+        assert_eq!(request.query_param("q2"), Some("last"));
+    }
+
+    #[test]
+    fn requests_round_trip_through_to_bytes() {
+        let original = HttpRequest::from_bytes(
+            b"POST /notify/a?x=1 HTTP/1.1\r\nHost: example.com\r\nX-Trace: abc\r\n\
+              Transfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+        )
+        .unwrap();
+        let bytes = original.to_bytes();
+        let text = String::from_utf8(bytes.clone()).unwrap();
+        assert!(text.starts_with("POST /notify/a?x=1 HTTP/1.1\r\n"));
+        assert!(!text.contains("Transfer-Encoding"));
+        assert!(text.ends_with("\r\nContent-Length: 5\r\n\r\nhello"));
+
+        let parsed = HttpRequest::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.method, original.method);
+        assert_eq!(parsed.target.raw, original.target.raw);
+        assert_eq!(parsed.body, original.body);
+        assert_eq!(parsed.headers[&HeaderType::Host], "example.com");
+        assert_eq!(
+            parsed.headers[&HeaderType::Custom("x-trace".to_owned())],
+            "abc"
+        );
+
+        let bodiless = HttpRequest::from_bytes(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(bodiless.to_bytes(), b"GET / HTTP/1.1\r\n\r\n");
+    }
+
+    #[test]
+    fn content_type_comes_from_the_extension() {
+        assert_eq!(content_type_for("index.HTML"), Some("text/html"));
+        assert_eq!(content_type_for("a/b/app.mjs"), Some("text/javascript"));
+        assert_eq!(content_type_for("logo.svg"), Some("image/svg+xml"));
+        assert_eq!(content_type_for("notes.txt"), Some("text/plain"));
+        assert_eq!(content_type_for("archive.xyz"), None);
+        assert_eq!(content_type_for("README"), None);
+    }
+
+    fn memory_files(files: &[(&str, &[u8])]) -> MemoryStorage {
+        let storage = MemoryStorage::default();
+        for (name, contents) in files {
+            storage.replace(name, contents).unwrap();
+        }
+        storage
+    }
+
+    fn get(storage: &dyn Storage, config: &Config, head: &str) -> HttpResponse {
+        let mut request = HttpRequest::from_bytes(head.as_bytes()).unwrap();
+        let name = request.target.path.trim_start_matches("/files/").to_owned();
+        request.params.insert("name".to_owned(), name);
+        get_file(&request, config, storage).unwrap_or_else(HttpError::into_response)
+    }
+
+    #[test]
+    fn unknown_extensions_get_the_default_mime() {
+        let storage = memory_files(&[("README", b"hi"), ("data.xyz", b"hi"), ("a.css", b"")]);
+        let config = Config {
+            default_mime: "text/plain".to_owned(),
+            ..Config::default()
+        };
+        for (path, expected) in [
+            ("/files/README", "text/plain"),
+            ("/files/data.xyz", "text/plain"),
+            ("/files/a.css", "text/css"),
+        ] {
+            let response = get(&storage, &config, &format!("GET {} HTTP/1.1\r\n\r\n", path));
+            assert_eq!(
+                response.headers[&HeaderType::ContentType],
+                expected,
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn every_negotiated_file_response_varies_on_accept_encoding() {
+        let storage = memory_files(&[("a.txt", b"hello world")]);
+        let config = Config::default();
+        let etag = get(&storage, &config, "GET /files/a.txt HTTP/1.1\r\n\r\n").headers
+            [&HeaderType::ETag]
+            .clone();
+        let streaming = Config {
+            stream_threshold: 1,
+            ..Config::default()
+        };
+        let cases = [
+            (
+                &config,
+                "Accept-Encoding: br, identity;q=0",
+                StatusCode::NotAcceptable,
+            ),
+            (
+                &config,
+                &format!("If-None-Match: {}", etag),
+                StatusCode::NotModified,
+            ),
+            (&config, "Range: bytes=0-4", StatusCode::PartialContent),
+            (&config, "Range: bytes=50-", StatusCode::RangeNotSatisfiable),
+            (&streaming, "Accept-Encoding: gzip", StatusCode::Ok),
+            (&config, "Accept-Encoding: gzip", StatusCode::Ok),
+        ];
+        for (config, header, status) in cases {
+            let head = format!("GET /files/a.txt HTTP/1.1\r\n{}\r\n\r\n", header);
+            let response = get(&storage, config, &head);
+            assert_eq!(response.status_code, status, "{}", header);
+            assert_eq!(
+                response.headers.get(&HeaderType::Vary).map(String::as_str),
+                Some("Accept-Encoding"),
+                "{}",
+                header
+            );
+        }
+    }
+
+    fn streaming_router() -> Router {
+        let mut router = Router::new();
+        router.get("/stream", |_| {
+            let mut response = HttpResponse::new(StatusCode::Ok);
+            response.body = Body::Chunked(Box::new(Cursor::new(vec![b'x'; 20_000])));
+            Ok(response)
+        });
+        router
+    }
+
+    fn body_bytes(response: HttpResponse) -> Vec<u8> {
+        match response.body {
+            Body::Bytes(bytes) => bytes,
+            body => panic!("expected an in-memory body, got {:?}", body),
+        }
+    }
+
+    #[test]
+    fn chunked_framing_decodes_to_the_original_bytes() {
+        let request = HttpRequest::from_bytes(b"GET /stream HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = handle_request(request, &Config::default(), &streaming_router());
+        assert_eq!(response.headers[&HeaderType::TransferEncoding], "chunked");
+        assert!(!response.headers.contains_key(&HeaderType::ContentLength));
+
+        let mut wire = Vec::new();
+        response.write_to(&mut wire).unwrap();
+        let parsed = HttpResponse::from_bytes(&wire).unwrap();
+        assert_eq!(body_bytes(parsed), vec![b'x'; 20_000]);
+    }
+
+    #[test]
+    fn http_1_0_gets_an_unframed_body_and_close() {
+        let request =
+            HttpRequest::from_bytes(b"GET /stream HTTP/1.0\r\nConnection: keep-alive\r\n\r\n")
+                .unwrap();
+        let mut response = handle_request(request, &Config::default(), &streaming_router());
+        assert!(!response.headers.contains_key(&HeaderType::TransferEncoding));
+        assert!(!response.headers.contains_key(&HeaderType::ContentLength));
+        assert_eq!(response.headers[&HeaderType::Connection], "close");
+
+        // The keep-alive the client asked for doesn't override it.
+        response.append_list_value(HeaderType::Connection, "keep-alive");
+        assert_eq!(response.headers[&HeaderType::Connection], "close");
+
+        let mut wire = Vec::new();
+        response.write_to(&mut wire).unwrap();
+        let head_end = wire.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        assert!(wire.starts_with(b"HTTP/1.0 200 OK\r\n"));
+        assert_eq!(&wire[head_end..], &vec![b'x'; 20_000][..]);
+    }
+}
@@ -0,0 +1,508 @@
+//! Pieces of HTTP/1.1 message parsing shared by requests and responses.
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, ErrorKind, Read},
+};
+
+use crate::{
+    HeaderType, StatusCode, DEFAULT_MAX_BODY_SIZE, DEFAULT_MAX_HEADER_BYTES,
+    DEFAULT_MAX_HEADER_COUNT, DEFAULT_MAX_HEADER_LINE, DEFAULT_MAX_REQUEST_LINE,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ParseError {
+    #[error("connection closed before request line")]
+    ConnectionClosed,
+    #[error("missing request method")]
+    MissingMethod,
+    #[error("missing request path")]
+    MissingPath,
+    #[error("invalid request target: {0}")]
+    InvalidTarget(String),
+    #[error("missing HTTP version")]
+    MissingVersion,
+    #[error("malformed request line")]
+    MalformedRequestLine,
+    #[error("request line too long")]
+    RequestLineTooLong,
+    #[error("status line too long")]
+    StatusLineTooLong,
+    #[error("header section too large")]
+    HeadersTooLarge,
+    #[error("invalid status code: {0}")]
+    InvalidStatus(String),
+    #[error("NUL byte in message head")]
+    NulByte,
+    #[error("malformed header line")]
+    MalformedHeader,
+    #[error("conflicting {0} headers")]
+    ConflictingHeaders(HeaderType),
+    #[error("unknown method: {0}")]
+    UnknownMethod(String),
+    #[error("invalid Content-Length: {0}")]
+    InvalidContentLength(String),
+    #[error("body of {0} bytes is too large")]
+    PayloadTooLarge(u64),
+    #[error("both Content-Length and Transfer-Encoding: chunked are present")]
+    AmbiguousLength,
+    #[error("request body not framed by chunked: {0}")]
+    UnframedTransferCoding(String),
+    #[error("Transfer-Encoding in an HTTP/1.0 request")]
+    TransferEncodingInHttp10,
+    #[error("invalid chunk size: {0}")]
+    InvalidChunkSize(String),
+    #[error("chunk data not followed by CRLF")]
+    MalformedChunk,
+    #[error("connection closed mid-message")]
+    UnexpectedEof,
+    #[error("timed out reading request head")]
+    Timeout,
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl ParseError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ParseError::PayloadTooLarge(_) => StatusCode::PayloadTooLarge,
+            ParseError::Timeout => StatusCode::RequestTimeout,
+            ParseError::RequestLineTooLong => StatusCode::UriTooLong,
+            ParseError::HeadersTooLarge => StatusCode::RequestHeaderFieldsTooLarge,
+            _ => StatusCode::BadRequest,
+        }
+    }
+}
+
+/// How much of a message the parser will read before giving up on it.
+/// Line lengths count the line ending.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub request_line: usize,
+    pub header_line: usize,
+    /// All header lines together, the blank line ending them included.
+    pub header_bytes: usize,
+    pub header_count: usize,
+    pub body: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            request_line: DEFAULT_MAX_REQUEST_LINE,
+            header_line: DEFAULT_MAX_HEADER_LINE,
+            header_bytes: DEFAULT_MAX_HEADER_BYTES,
+            header_count: DEFAULT_MAX_HEADER_COUNT,
+            body: DEFAULT_MAX_BODY_SIZE,
+        }
+    }
+}
+
+/// Reads one line, failing with `too_long()` as soon as `limit` bytes have
+/// been read without reaching its end. Returns an empty string at EOF.
+pub fn read_line_limited<R: BufRead>(
+    reader: &mut R,
+    limit: usize,
+    too_long: impl FnOnce() -> ParseError,
+) -> Result<String, ParseError> {
+    let mut line = String::new();
+    reader.by_ref().take(limit as u64).read_line(&mut line)?;
+    if line.len() == limit && !line.ends_with('\n') {
+        return Err(too_long());
+    }
+    Ok(line)
+}
+
+/// NUL is valid UTF-8 but never valid in a start line or field, and can
+/// truncate values in code that treats them as C strings.
+pub fn reject_nul(line: &str) -> Result<(), ParseError> {
+    if line.contains('\0') {
+        return Err(ParseError::NulByte);
+    }
+    Ok(())
+}
+
+/// Reads header lines up to and including the blank line ending the head.
+/// A repeated field replaces the earlier one, except that differing
+/// `Content-Length` or `Transfer-Encoding` values are refused: they
+/// leave the framing open to interpretation.
+pub fn read_headers<R: BufRead>(
+    reader: &mut R,
+    limits: &Limits,
+) -> Result<HashMap<HeaderType, String>, ParseError> {
+    let mut headers = HashMap::new();
+    let (mut total, mut count) = (0, 0);
+    loop {
+        let limit = limits.header_line.min(limits.header_bytes - total);
+        let line = read_line_limited(reader, limit, || ParseError::HeadersTooLarge)?;
+        if line.is_empty() {
+            return Err(ParseError::UnexpectedEof);
+        }
+        total += line.len();
+        if line.trim().is_empty() {
+            break;
+        }
+        count += 1;
+        if count > limits.header_count {
+            return Err(ParseError::HeadersTooLarge);
+        }
+        reject_nul(&line)?;
+        let (header_type, value) = HeaderType::parse(&line).ok_or(ParseError::MalformedHeader)?;
+        let framing = matches!(
+            header_type,
+            HeaderType::ContentLength | HeaderType::TransferEncoding
+        );
+        if framing && headers.get(&header_type).is_some_and(|old| *old != value) {
+            return Err(ParseError::ConflictingHeaders(header_type));
+        }
+        headers.insert(header_type, value);
+    }
+    Ok(headers)
+}
+
+/// Reads a message body framed by `Transfer-Encoding: chunked` or
+/// `Content-Length`. Without either, requests have no body while responses
+/// run to the end of the input, which `until_eof` selects. Bodies over
+/// the limit are refused, before any of a declared length is read.
+///
+/// A `Transfer-Encoding` whose last coding isn't `chunked` leaves the
+/// length to the end of the connection (RFC 9112 section 6.3). Responses
+/// are read that way; requests are refused, since reading them by
+/// `Content-Length` or as bodiless would desync the connection.
+pub fn read_body<R: BufRead>(
+    reader: &mut R,
+    headers: &mut HashMap<HeaderType, String>,
+    until_eof: bool,
+    limits: &Limits,
+) -> Result<Vec<u8>, ParseError> {
+    let max_size = limits.body;
+    let transfer_encoding = headers.get(&HeaderType::TransferEncoding);
+    let chunked = transfer_encoding
+        .and_then(|codings| codings.rsplit(',').next())
+        .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"));
+    if let Some(codings) = transfer_encoding.filter(|_| !chunked && !until_eof) {
+        return Err(ParseError::UnframedTransferCoding(codings.clone()));
+    }
+
+    let mut body = Vec::new();
+    if chunked {
+        if headers.contains_key(&HeaderType::ContentLength) {
+            return Err(ParseError::AmbiguousLength);
+        }
+        body = read_chunked_body(reader, headers, limits)?;
+    } else if let Some(content_length_str) = headers
+        .get(&HeaderType::ContentLength)
+        .filter(|_| transfer_encoding.is_none())
+    {
+        // Only bare digits: `parse` would also take a leading `+`, which
+        // other parsers on the path may read differently.
+        let content_length: u64 = Some(content_length_str)
+            .filter(|len| len.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|len| len.parse().ok())
+            .ok_or_else(|| ParseError::InvalidContentLength(content_length_str.clone()))?;
+        if content_length > max_size {
+            return Err(ParseError::PayloadTooLarge(content_length));
+        }
+        body.resize(content_length as usize, 0);
+        read_body_bytes(reader, &mut body)?;
+    } else if until_eof {
+        reader.take(max_size + 1).read_to_end(&mut body)?;
+        if body.len() as u64 > max_size {
+            return Err(ParseError::PayloadTooLarge(body.len() as u64));
+        }
+    }
+    Ok(body)
+}
+
+/// Splits a status line such as `HTTP/1.1 404 Not Found` into the version
+/// and status code. The reason phrase is informational and ignored. Any
+/// three-digit code from 100 to 599 is accepted; one we have no variant
+/// for is read as the `x00` of its class, as RFC 9110 section 15 tells
+/// clients to do.
+pub fn parse_status_line(line: &str) -> Result<(String, StatusCode), ParseError> {
+    let mut parts = line.trim_end_matches(['\r', '\n']).splitn(3, ' ');
+    let version = parts
+        .next()
+        .filter(|version| version.starts_with("HTTP/"))
+        .ok_or(ParseError::MissingVersion)?;
+    let code = parts.next().unwrap_or_default();
+    let status_code = Some(code)
+        .filter(|code| code.len() == 3 && code.bytes().all(|b| b.is_ascii_digit()))
+        .and_then(|code| code.parse::<u16>().ok())
+        .filter(|code| (100..=599).contains(code))
+        .and_then(|code| StatusCode::from_code(code).or(StatusCode::from_code(code / 100 * 100)))
+        .ok_or_else(|| ParseError::InvalidStatus(code.to_owned()))?;
+    Ok((version.to_owned(), status_code))
+}
+
+fn read_body_bytes<R: BufRead>(reader: &mut R, buf: &mut [u8]) -> Result<(), ParseError> {
+    reader.read_exact(buf).map_err(|err| match err.kind() {
+        ErrorKind::UnexpectedEof => ParseError::UnexpectedEof,
+        _ => ParseError::Io(err),
+    })
+}
+
+/// Decodes a `Transfer-Encoding: chunked` body. Trailer fields after the
+/// last chunk are merged into `headers`, except for fields that could
+/// change how the message is framed or routed, which are dropped.
+fn read_chunked_body<R: BufRead>(
+    reader: &mut R,
+    headers: &mut HashMap<HeaderType, String>,
+    limits: &Limits,
+) -> Result<Vec<u8>, ParseError> {
+    let mut body = Vec::new();
+    loop {
+        let size_line = read_line_limited(reader, limits.header_line, || {
+            ParseError::InvalidChunkSize("chunk size line too long".to_owned())
+        })?;
+        if size_line.is_empty() {
+            return Err(ParseError::UnexpectedEof);
+        }
+        // Chunk extensions after `;` carry nothing we act on.
+        let size = size_line.split(';').next().unwrap_or_default().trim();
+        // Sizes that don't fit in a u64 are malformed rather than merely too big.
+        let size = Some(size)
+            .filter(|size| size.bytes().all(|b| b.is_ascii_hexdigit()))
+            .and_then(|size| u64::from_str_radix(size, 16).ok())
+            .ok_or_else(|| ParseError::InvalidChunkSize(size.to_owned()))?;
+        if size == 0 {
+            break;
+        }
+
+        // Checked before adding, since the client picks `size`.
+        let start = body.len();
+        if size > limits.body.saturating_sub(start as u64) {
+            return Err(ParseError::PayloadTooLarge(
+                (start as u64).saturating_add(size),
+            ));
+        }
+        body.resize(start + size as usize, 0);
+        read_body_bytes(reader, &mut body[start..])?;
+
+        let mut crlf = [0; 2];
+        read_body_bytes(reader, &mut crlf)?;
+        if crlf != *b"\r\n" {
+            return Err(ParseError::MalformedChunk);
+        }
+    }
+
+    for (header_type, value) in read_headers(reader, limits)? {
+        if header_type.allowed_in_trailer() {
+            headers.insert(header_type, value);
+        }
+    }
+
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn chunked_body(data: &[u8], limits: &Limits) -> Result<Vec<u8>, ParseError> {
+        let mut headers = HashMap::new();
+        headers.insert(HeaderType::TransferEncoding, "chunked".to_owned());
+        read_body(&mut Cursor::new(data), &mut headers, false, limits)
+    }
+
+    fn headers(data: &[u8]) -> Result<HashMap<HeaderType, String>, ParseError> {
+        read_headers(&mut Cursor::new(data), &Limits::default())
+    }
+
+    fn sized_body(content_length: &str, data: &[u8]) -> Result<Vec<u8>, ParseError> {
+        let mut headers = HashMap::new();
+        headers.insert(HeaderType::ContentLength, content_length.to_owned());
+        read_body(
+            &mut Cursor::new(data),
+            &mut headers,
+            false,
+            &Limits::default(),
+        )
+    }
+
+    #[test]
+    fn content_length_is_parsed_as_u64() {
+        let over_u32 = u64::from(u32::MAX) + 1;
+        let err = sized_body(&over_u32.to_string(), b"").unwrap_err();
+        assert!(matches!(err, ParseError::PayloadTooLarge(len) if len == over_u32));
+        assert_eq!(err.status_code(), StatusCode::PayloadTooLarge);
+
+        let err = sized_body(&u64::MAX.to_string(), b"").unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::PayloadTooLarge);
+        for invalid in ["18446744073709551616", "-1", "+5", "5 5", ""] {
+            assert!(
+                matches!(
+                    sized_body(invalid, b"hello"),
+                    Err(ParseError::InvalidContentLength(_))
+                ),
+                "{:?}",
+                invalid
+            );
+        }
+        assert_eq!(sized_body("5", b"hello").unwrap(), b"hello");
+    }
+
+    #[test]
+    fn header_names_are_case_insensitive() {
+        let headers = headers(b"content-length: 5\r\nTRANSFER-ENCODING: chunked\r\n\r\n").unwrap();
+        assert_eq!(headers[&HeaderType::ContentLength], "5");
+        assert_eq!(headers[&HeaderType::TransferEncoding], "chunked");
+    }
+
+    #[test]
+    fn conflicting_framing_headers_are_refused() {
+        let err = headers(b"Content-Length: 5\r\ncontent-length: 6\r\n\r\n").unwrap_err();
+        assert!(matches!(
+            err,
+            ParseError::ConflictingHeaders(HeaderType::ContentLength)
+        ));
+        assert_eq!(err.status_code(), StatusCode::BadRequest);
+
+        let err =
+            headers(b"Transfer-Encoding: chunked\r\nTransfer-Encoding: gzip\r\n\r\n").unwrap_err();
+        assert!(matches!(
+            err,
+            ParseError::ConflictingHeaders(HeaderType::TransferEncoding)
+        ));
+    }
+
+    #[test]
+    fn repeated_identical_content_length_is_accepted() {
+        let headers = headers(b"Content-Length: 5\r\nContent-Length: 5\r\n\r\n").unwrap();
+        assert_eq!(headers[&HeaderType::ContentLength], "5");
+    }
+
+    #[test]
+    fn whitespace_in_header_names_is_refused() {
+        for data in [
+            &b"Content-Length : 5\r\n\r\n"[..],
+            b"Host: a\r\n folded\r\n\r\n",
+            b": empty\r\n\r\n",
+            b"no colon\r\n\r\n",
+        ] {
+            assert!(matches!(headers(data), Err(ParseError::MalformedHeader)));
+        }
+    }
+
+    #[test]
+    fn lowercase_transfer_encoding_with_content_length_is_ambiguous() {
+        let mut headers =
+            headers(b"content-length: 3\r\ntransfer-encoding: chunked\r\n\r\n").unwrap();
+        let err = read_body(
+            &mut Cursor::new(b"0\r\n\r\n"),
+            &mut headers,
+            false,
+            &Limits::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, ParseError::AmbiguousLength));
+    }
+
+    #[test]
+    fn requests_must_end_their_transfer_codings_with_chunked() {
+        for head in [
+            &b"Transfer-Encoding: gzip\r\n\r\n"[..],
+            b"Transfer-Encoding: gzip\r\nContent-Length: 5\r\n\r\n",
+            b"Transfer-Encoding: chunked, gzip\r\n\r\n",
+            b"Transfer-Encoding: x-unknown\r\nContent-Length: 5\r\n\r\n",
+        ] {
+            let mut headers = headers(head).unwrap();
+            let err = read_body(
+                &mut Cursor::new(b"hello"),
+                &mut headers,
+                false,
+                &Limits::default(),
+            )
+            .unwrap_err();
+            assert!(
+                matches!(err, ParseError::UnframedTransferCoding(_)),
+                "{:?}",
+                err
+            );
+            assert_eq!(err.status_code(), StatusCode::BadRequest);
+        }
+
+        let mut headers = headers(b"Transfer-Encoding: gzip, chunked\r\n\r\n").unwrap();
+        let body = read_body(
+            &mut Cursor::new(b"2\r\nhi\r\n0\r\n\r\n"),
+            &mut headers,
+            false,
+            &Limits::default(),
+        );
+        assert_eq!(body.unwrap(), b"hi");
+    }
+
+    #[test]
+    fn responses_without_final_chunked_run_to_eof() {
+        let mut headers = headers(b"Transfer-Encoding: gzip\r\nContent-Length: 2\r\n\r\n").unwrap();
+        let body = read_body(
+            &mut Cursor::new(b"hello"),
+            &mut headers,
+            true,
+            &Limits::default(),
+        );
+        assert_eq!(body.unwrap(), b"hello");
+    }
+
+    #[test]
+    fn header_limits_are_enforced() {
+        let limits = Limits {
+            header_count: 2,
+            ..Limits::default()
+        };
+        let data = b"A: 1\r\nB: 2\r\nC: 3\r\n\r\n";
+        let err = read_headers(&mut Cursor::new(data), &limits).unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::RequestHeaderFieldsTooLarge);
+
+        let limits = Limits {
+            header_line: 8,
+            ..Limits::default()
+        };
+        let err = read_headers(&mut Cursor::new(b"Host: example\r\n\r\n"), &limits).unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::RequestHeaderFieldsTooLarge);
+    }
+
+    #[test]
+    fn nul_in_a_header_is_refused() {
+        assert!(matches!(
+            headers(b"Host: a\0b\r\n\r\n"),
+            Err(ParseError::NulByte)
+        ));
+    }
+
+    #[test]
+    fn decodes_chunked_body() {
+        let body = chunked_body(
+            b"3\r\nabc\r\n2;ext=1\r\nde\r\n0\r\n\r\n",
+            &Limits::default(),
+        );
+        assert_eq!(body.unwrap(), b"abcde");
+    }
+
+    #[test]
+    fn chunk_size_overflowing_u64_is_bad_request() {
+        let err = chunked_body(b"1ffffffffffffffff\r\n", &Limits::default()).unwrap_err();
+        assert!(matches!(err, ParseError::InvalidChunkSize(_)));
+        assert_eq!(err.status_code(), StatusCode::BadRequest);
+    }
+
+    #[test]
+    fn chunk_sizes_summing_past_u64_are_too_large() {
+        let err = chunked_body(b"1\r\na\r\nffffffffffffffff\r\n", &Limits::default()).unwrap_err();
+        assert!(matches!(err, ParseError::PayloadTooLarge(_)));
+        assert_eq!(err.status_code(), StatusCode::PayloadTooLarge);
+    }
+
+    #[test]
+    fn chunks_over_the_limit_in_total_are_too_large() {
+        let limits = Limits {
+            body: 5,
+            ..Limits::default()
+        };
+        let err = chunked_body(b"3\r\nabc\r\n3\r\ndef\r\n0\r\n\r\n", &limits).unwrap_err();
+        assert!(matches!(err, ParseError::PayloadTooLarge(6)));
+        assert_eq!(err.status_code(), StatusCode::PayloadTooLarge);
+    }
+}
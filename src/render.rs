@@ -0,0 +1,200 @@
+use std::fmt::Write;
+
+/// Formats the built-in endpoints can answer in, picked from `Accept`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Representation {
+    Text,
+    Html,
+    Json,
+}
+
+impl Representation {
+    const ALL: [Representation; 3] = [
+        Representation::Text,
+        Representation::Html,
+        Representation::Json,
+    ];
+
+    pub fn media_type(&self) -> &'static str {
+        match self {
+            Representation::Text => "text/plain",
+            Representation::Html => "text/html",
+            Representation::Json => "application/json",
+        }
+    }
+
+    /// Chooses a representation from an `Accept` value. Each candidate takes
+    /// the q-value of the most specific range matching it (`type/subtype`
+    /// over `type/*` over `*/*`), the highest q wins and ties go to
+    /// `default`. Without a usable `Accept` the default is kept.
+    pub fn negotiate(accept: Option<&str>, default: Representation) -> Representation {
+        let Some(accept) = accept else {
+            return default;
+        };
+
+        let mut ranges = Vec::new();
+        for item in accept.split(',') {
+            let mut params = item.split(';');
+            let range = params
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_ascii_lowercase();
+            let Some((kind, subtype)) = range.split_once('/') else {
+                continue;
+            };
+            let mut q = 1.0;
+            for param in params {
+                if let Some((name, value)) = param.split_once('=') {
+                    if name.trim().eq_ignore_ascii_case("q") {
+                        q = value.trim().parse().unwrap_or(0.0);
+                    }
+                }
+            }
+            ranges.push((kind.to_owned(), subtype.to_owned(), q));
+        }
+
+        let quality = |representation: Representation| {
+            let (kind, subtype) = representation.media_type().split_once('/').unwrap();
+            ranges
+                .iter()
+                .filter_map(|(range_kind, range_subtype, q)| {
+                    let specificity = match (range_kind.as_str(), range_subtype.as_str()) {
+                        (k, s) if k == kind && s == subtype => 2,
+                        (k, "*") if k == kind => 1,
+                        ("*", "*") => 0,
+                        _ => return None,
+                    };
+                    Some((specificity, *q))
+                })
+                .max_by_key(|(specificity, _)| *specificity)
+                .map_or(0.0, |(_, q)| q)
+        };
+
+        let mut best = (default, quality(default));
+        for representation in Self::ALL {
+            let q = quality(representation);
+            if q > best.1 {
+                best = (representation, q);
+            }
+        }
+        if best.1 > 0.0 {
+            best.0
+        } else {
+            default
+        }
+    }
+}
+
+/// Data behind a built-in endpoint, renderable in each representation.
+pub trait Render {
+    fn text(&self) -> String;
+    fn html(&self) -> String;
+    fn json(&self) -> String;
+
+    fn render(&self, representation: Representation) -> String {
+        match representation {
+            Representation::Text => self.text(),
+            Representation::Html => self.html(),
+            Representation::Json => self.json(),
+        }
+    }
+}
+
+/// Escapes text for use in HTML element content and quoted attributes.
+pub fn html_escape(input: &str) -> String {
+    let mut escaped = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Quotes and escapes a string as a JSON string literal.
+pub fn json_string(input: &str) -> String {
+    let mut quoted = String::with_capacity(input.len() + 2);
+    quoted.push('"');
+    for c in input.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn negotiate(accept: &str) -> Representation {
+        Representation::negotiate(Some(accept), Representation::Text)
+    }
+
+    #[test]
+    fn higher_q_wins_across_wildcards() {
+        assert_eq!(
+            negotiate("text/*;q=0.5, application/json;q=0.9"),
+            Representation::Json
+        );
+        assert_eq!(
+            negotiate("text/html;q=0.8, application/json;q=0.2"),
+            Representation::Html
+        );
+        assert_eq!(negotiate("application/*"), Representation::Json);
+    }
+
+    #[test]
+    fn the_most_specific_range_sets_the_q_value() {
+        // text/plain is refused outright even though text/* allows it.
+        assert_eq!(negotiate("text/*, text/plain;q=0"), Representation::Html);
+        assert_eq!(negotiate("*/*;q=0.1, text/html"), Representation::Html);
+        assert_eq!(
+            negotiate("*/*, text/*;q=0.2, text/html;q=0.3"),
+            Representation::Json
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_default() {
+        assert_eq!(negotiate("*/*"), Representation::Text);
+        assert_eq!(
+            Representation::negotiate(Some("*/*"), Representation::Html),
+            Representation::Html
+        );
+        assert_eq!(negotiate("image/png"), Representation::Text);
+        assert_eq!(negotiate("*/*;q=0"), Representation::Text);
+        assert_eq!(negotiate("nonsense, ;q=1"), Representation::Text);
+        assert_eq!(
+            Representation::negotiate(None, Representation::Json),
+            Representation::Json
+        );
+    }
+
+    #[test]
+    fn escapes_for_html_and_json() {
+        assert_eq!(
+            html_escape(r#"<a href="x">Tom & 'Jerry'</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; &#39;Jerry&#39;&lt;/a&gt;"
+        );
+        assert_eq!(
+            json_string("say \"hi\"\\\n\t\u{1}é"),
+            r#""say \"hi\"\\\n\t\u0001é""#
+        );
+    }
+}
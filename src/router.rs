@@ -0,0 +1,427 @@
+use std::{collections::HashMap, io};
+
+use crate::{
+    storage::StorageError, Error, HeaderType, HttpRequest, HttpResponse, Method, StatusCode,
+    UNAVAILABLE_RETRY_AFTER_SECS,
+};
+
+pub type Handler = Box<dyn Fn(&HttpRequest) -> Result<HttpResponse, HttpError> + Send + Sync>;
+
+/// Why a handler couldn't produce its response. The dispatcher turns it
+/// into one, so handlers can use `?` instead of building error responses.
+#[derive(Debug, thiserror::Error)]
+pub enum HttpError {
+    #[error("bad request")]
+    BadRequest,
+    #[error("not found")]
+    NotFound,
+    #[error("no acceptable content coding")]
+    NotAcceptable,
+    #[error("precondition failed")]
+    PreconditionFailed,
+    #[error("URI too long")]
+    UriTooLong,
+    #[error("service unavailable")]
+    Unavailable,
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    #[error("{0}")]
+    Internal(Error),
+}
+
+impl From<io::Error> for HttpError {
+    fn from(err: io::Error) -> Self {
+        HttpError::Internal(err.into())
+    }
+}
+
+impl From<Error> for HttpError {
+    fn from(err: Error) -> Self {
+        HttpError::Internal(err)
+    }
+}
+
+impl HttpError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            HttpError::BadRequest => StatusCode::BadRequest,
+            HttpError::NotFound | HttpError::Storage(StorageError::NotFound) => {
+                StatusCode::NotFound
+            }
+            HttpError::NotAcceptable => StatusCode::NotAcceptable,
+            HttpError::PreconditionFailed => StatusCode::PreconditionFailed,
+            HttpError::UriTooLong => StatusCode::UriTooLong,
+            HttpError::Storage(StorageError::Forbidden) => StatusCode::Forbidden,
+            HttpError::Unavailable | HttpError::Storage(StorageError::Unavailable) => {
+                StatusCode::ServiceUnavailable
+            }
+            HttpError::Storage(StorageError::ReadOnly) => StatusCode::MethodNotAllowed,
+            HttpError::Storage(StorageError::Io(_)) | HttpError::Internal(_) => {
+                StatusCode::InternalServerError
+            }
+        }
+    }
+
+    /// The response for this error: its status, any header the status
+    /// calls for, and for server errors the message as the body.
+    pub fn into_response(self) -> HttpResponse {
+        let mut response = HttpResponse::new(self.status_code());
+        match &self {
+            HttpError::Unavailable | HttpError::Storage(StorageError::Unavailable) => {
+                response.headers.insert(
+                    HeaderType::RetryAfter,
+                    UNAVAILABLE_RETRY_AFTER_SECS.to_string(),
+                );
+            }
+            // Only content-coding negotiation fails this way, and another
+            // Accept-Encoding could succeed.
+            HttpError::NotAcceptable => {
+                response
+                    .append_list_value(HeaderType::Vary, &HeaderType::AcceptEncoding.to_string());
+            }
+            HttpError::Storage(StorageError::ReadOnly) => {
+                response
+                    .headers
+                    .insert(HeaderType::Allow, "GET, HEAD, OPTIONS".to_owned());
+            }
+            HttpError::Storage(StorageError::Io(_)) | HttpError::Internal(_) => {
+                response.body = self.to_string().into();
+            }
+            _ => {}
+        }
+        response
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    /// `:name`, matches exactly one path segment.
+    Param(String),
+    /// `*name`, matches the rest of the path, slashes included.
+    Rest(String),
+}
+
+struct Route {
+    method: Method,
+    pattern: Vec<Segment>,
+    handler: Handler,
+}
+
+/// Maps `(Method, pattern)` pairs to handlers. Patterns are literal segments
+/// plus `:param` for a single segment or a trailing `*param` for the rest
+/// of the path; captured values are available through
+/// [`HttpRequest::param`].
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn route<F>(&mut self, method: Method, pattern: &str, handler: F) -> &mut Self
+    where
+        F: Fn(&HttpRequest) -> Result<HttpResponse, HttpError> + Send + Sync + 'static,
+    {
+        let pattern = split_path(pattern)
+            .map(|segment| {
+                if let Some(name) = segment.strip_prefix(':') {
+                    Segment::Param(name.to_owned())
+                } else if let Some(name) = segment.strip_prefix('*') {
+                    Segment::Rest(name.to_owned())
+                } else {
+                    Segment::Literal(segment.to_owned())
+                }
+            })
+            .collect();
+        self.routes.push(Route {
+            method,
+            pattern,
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    pub fn get<F>(&mut self, pattern: &str, handler: F) -> &mut Self
+    where
+        F: Fn(&HttpRequest) -> Result<HttpResponse, HttpError> + Send + Sync + 'static,
+    {
+        self.route(Method::Get, pattern, handler)
+    }
+
+    pub fn post<F>(&mut self, pattern: &str, handler: F) -> &mut Self
+    where
+        F: Fn(&HttpRequest) -> Result<HttpResponse, HttpError> + Send + Sync + 'static,
+    {
+        self.route(Method::Post, pattern, handler)
+    }
+
+    pub fn put<F>(&mut self, pattern: &str, handler: F) -> &mut Self
+    where
+        F: Fn(&HttpRequest) -> Result<HttpResponse, HttpError> + Send + Sync + 'static,
+    {
+        self.route(Method::Put, pattern, handler)
+    }
+
+    /// Runs the handler registered for the request's method and path. HEAD
+    /// falls back to the GET handler. A path that matches under other
+    /// methods only gets 405, or 200 for OPTIONS, with an `Allow` header;
+    /// anything else is 404. A handler's error becomes the response for
+    /// its status code.
+    pub fn dispatch(&self, request: &mut HttpRequest) -> HttpResponse {
+        let matching: Vec<_> = self
+            .routes
+            .iter()
+            .filter_map(|route| {
+                match_pattern(&route.pattern, &request.target.segments).map(|p| (route, p))
+            })
+            .collect();
+        if matching.is_empty() {
+            return HttpResponse::new(StatusCode::NotFound);
+        }
+
+        let selected = matching
+            .iter()
+            .find(|(route, _)| route.method == request.method)
+            .or_else(|| {
+                (request.method == Method::Head)
+                    .then(|| {
+                        matching
+                            .iter()
+                            .find(|(route, _)| route.method == Method::Get)
+                    })
+                    .flatten()
+            });
+        if let Some((route, (params, rest))) = selected {
+            request.params = params.clone();
+            request.rest = rest.clone();
+            return (route.handler)(request).unwrap_or_else(HttpError::into_response);
+        }
+
+        let mut allowed: Vec<Method> = Vec::new();
+        for (route, _) in &matching {
+            if !allowed.contains(&route.method) {
+                allowed.push(route.method);
+            }
+            if route.method == Method::Get && !allowed.contains(&Method::Head) {
+                allowed.push(Method::Head);
+            }
+        }
+        allowed.push(Method::Options);
+        let allowed = allowed
+            .iter()
+            .map(Method::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let status = if request.method == Method::Options {
+            StatusCode::Ok
+        } else {
+            StatusCode::MethodNotAllowed
+        };
+        let mut response = HttpResponse::new(status);
+        response.headers.insert(HeaderType::Allow, allowed);
+        response
+    }
+}
+
+fn split_path(path: &str) -> std::str::Split<'_, char> {
+    path.strip_prefix('/').unwrap_or(path).split('/')
+}
+
+/// Matches decoded path segments against a pattern, returning the captured
+/// params and the segments a trailing `*name` took.
+fn match_pattern(
+    pattern: &[Segment],
+    segments: &[String],
+) -> Option<(HashMap<String, String>, Vec<String>)> {
+    let mut params = HashMap::new();
+    let mut segments = segments.iter();
+
+    for expected in pattern {
+        match expected {
+            Segment::Rest(name) => {
+                let rest: Vec<String> = segments.cloned().collect();
+                params.insert(name.clone(), rest.join("/"));
+                return Some((params, rest));
+            }
+            Segment::Literal(literal) => {
+                if segments.next()? != literal {
+                    return None;
+                }
+            }
+            Segment::Param(name) => {
+                params.insert(name.clone(), segments.next()?.clone());
+            }
+        }
+    }
+
+    segments.next().is_none().then_some((params, Vec::new()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::Body;
+
+    fn request(method: &str, path: &str) -> HttpRequest {
+        let head = format!("{} {} HTTP/1.1\r\n\r\n", method, path);
+        HttpRequest::from_bytes(head.as_bytes()).unwrap()
+    }
+
+    fn dispatch(router: &Router, method: &str, path: &str) -> HttpResponse {
+        router.dispatch(&mut request(method, path))
+    }
+
+    fn labelled(label: &'static str) -> impl Fn(&HttpRequest) -> Result<HttpResponse, HttpError> {
+        move |_| {
+            let mut response = HttpResponse::new(StatusCode::Ok);
+            response
+                .headers
+                .insert(HeaderType::Custom("x-route".to_owned()), label.to_owned());
+            Ok(response)
+        }
+    }
+
+    fn route_of(response: &HttpResponse) -> Option<&str> {
+        response
+            .headers
+            .get(&HeaderType::Custom("x-route".to_owned()))
+            .map(String::as_str)
+    }
+
+    #[test]
+    fn dispatches_to_the_matching_route() {
+        let mut router = Router::new();
+        router.get("/", labelled("root"));
+        router.get("/user-agent", labelled("user-agent"));
+        router.post("/user-agent", labelled("post"));
+
+        assert_eq!(route_of(&dispatch(&router, "GET", "/")), Some("root"));
+        assert_eq!(
+            route_of(&dispatch(&router, "GET", "/user-agent")),
+            Some("user-agent")
+        );
+        assert_eq!(
+            route_of(&dispatch(&router, "POST", "/user-agent")),
+            Some("post")
+        );
+        let missing = dispatch(&router, "GET", "/user-agent/extra");
+        assert_eq!(missing.status_code, StatusCode::NotFound);
+    }
+
+    #[test]
+    fn captures_params_and_the_rest_of_the_path() {
+        let mut router = Router::new();
+        router.get("/echo/:message", |request| {
+            let mut response = HttpResponse::new(StatusCode::Ok);
+            response.body = request
+                .param("message")
+                .unwrap_or_default()
+                .to_owned()
+                .into();
+            Ok(response)
+        });
+        router.get("/files/*name", |request| {
+            let mut response = HttpResponse::new(StatusCode::Ok);
+            response.body = request.param("name").unwrap_or_default().to_owned().into();
+            Ok(response)
+        });
+
+        let body = |response: HttpResponse| match response.body {
+            Body::Bytes(bytes) => String::from_utf8(bytes).unwrap(),
+            body => panic!("unexpected body {:?}", body),
+        };
+        assert_eq!(body(dispatch(&router, "GET", "/echo/hello")), "hello");
+        assert_eq!(
+            body(dispatch(&router, "GET", "/files/a/b/c.txt")),
+            "a/b/c.txt"
+        );
+        assert_eq!(body(dispatch(&router, "GET", "/files/")), "");
+        // Segments are matched decoded, and `%2F` doesn't split one.
+        assert_eq!(body(dispatch(&router, "GET", "/echo/a%2Fb")), "a/b");
+        assert_eq!(body(dispatch(&router, "GET", "/%65cho/hi")), "hi");
+        // `:param` matches exactly one segment.
+        assert_eq!(
+            dispatch(&router, "GET", "/echo/a/b").status_code,
+            StatusCode::NotFound
+        );
+        assert_eq!(
+            dispatch(&router, "GET", "/echo").status_code,
+            StatusCode::NotFound
+        );
+    }
+
+    #[test]
+    fn handler_errors_become_their_status() {
+        let mut router = Router::new();
+        router.get("/missing", |_| Err(HttpError::NotFound));
+        router.get("/busy", |_| Err(HttpError::Unavailable));
+        router.get("/broken", |_| Err(io::Error::other("disk on fire").into()));
+        router.put("/ro", |_| Err(StorageError::ReadOnly.into()));
+
+        let missing = dispatch(&router, "GET", "/missing");
+        assert_eq!(missing.status_code, StatusCode::NotFound);
+
+        let busy = dispatch(&router, "GET", "/busy");
+        assert_eq!(busy.status_code, StatusCode::ServiceUnavailable);
+        assert_eq!(
+            busy.headers[&HeaderType::RetryAfter],
+            UNAVAILABLE_RETRY_AFTER_SECS.to_string()
+        );
+
+        let broken = dispatch(&router, "GET", "/broken");
+        assert_eq!(broken.status_code, StatusCode::InternalServerError);
+        assert_eq!(broken.body.len(), Some("disk on fire".len() as u64));
+
+        let read_only = dispatch(&router, "PUT", "/ro");
+        assert_eq!(read_only.status_code, StatusCode::MethodNotAllowed);
+        assert_eq!(read_only.headers[&HeaderType::Allow], "GET, HEAD, OPTIONS");
+    }
+
+    #[test]
+    fn other_methods_get_405_with_allow() {
+        let mut router = Router::new();
+        router.get("/files/*name", labelled("get"));
+        router.post("/notify/:id", labelled("notify"));
+
+        let put = dispatch(&router, "PUT", "/files/x");
+        assert_eq!(put.status_code, StatusCode::MethodNotAllowed);
+        assert_eq!(put.headers[&HeaderType::Allow], "GET, HEAD, OPTIONS");
+
+        let get = dispatch(&router, "GET", "/notify/1");
+        assert_eq!(get.status_code, StatusCode::MethodNotAllowed);
+        assert_eq!(get.headers[&HeaderType::Allow], "POST, OPTIONS");
+
+        let options = dispatch(&router, "OPTIONS", "/files/x");
+        assert_eq!(options.status_code, StatusCode::Ok);
+        assert_eq!(options.headers[&HeaderType::Allow], "GET, HEAD, OPTIONS");
+
+        // HEAD falls back to the GET handler rather than getting 405.
+        assert_eq!(
+            route_of(&dispatch(&router, "HEAD", "/files/x")),
+            Some("get")
+        );
+    }
+
+    #[test]
+    fn match_pattern_captures() {
+        let pattern = |p: &str| {
+            let mut router = Router::new();
+            router.get(p, labelled(""));
+            router.routes.pop().unwrap().pattern
+        };
+        let segments = |path: &str| split_path(path).map(str::to_owned).collect::<Vec<_>>();
+        let (params, rest) =
+            match_pattern(&pattern("/a/:id/*rest"), &segments("/a/7/x/y")).unwrap();
+        assert_eq!(params["id"], "7");
+        assert_eq!(params["rest"], "x/y");
+        assert_eq!(rest, ["x", "y"]);
+        assert!(match_pattern(&pattern("/a/:id"), &segments("/b/7")).is_none());
+        assert!(match_pattern(&pattern("/a"), &segments("/a/")).is_none());
+    }
+}
@@ -0,0 +1,1003 @@
+//! Backends for the file mounts. Handlers only see readers and
+//! [`FileInfo`], so ranges, validators and streaming work the same on any
+//! of them.
+
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeSet, HashMap},
+    fs::{metadata, read_dir, remove_file, rename, symlink_metadata, write, File, Metadata},
+    hash::{Hash, Hasher},
+    io::{self, Cursor, ErrorKind, Read, Seek, SeekFrom},
+    path::{Component, Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum StorageError {
+    #[error("no such file")]
+    NotFound,
+    #[error("name is outside the mount")]
+    Forbidden,
+    #[error("storage is unavailable")]
+    Unavailable,
+    #[error("storage is read-only")]
+    ReadOnly,
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// What a backend knows about a stored file.
+#[derive(Debug, Clone, Copy)]
+pub struct FileInfo {
+    pub len: u64,
+    pub modified: Option<SystemTime>,
+    /// Set by backends where size and mtime alone don't identify the
+    /// content, and folded into the ETag.
+    pub identity: Option<u64>,
+}
+
+impl From<&Metadata> for FileInfo {
+    fn from(metadata: &Metadata) -> Self {
+        Self {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            identity: None,
+        }
+    }
+}
+
+pub trait ReadSeek: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> ReadSeek for T {}
+
+/// File operations behind a mount. Names are the decoded path below the
+/// mount point, `/`-separated.
+pub trait Storage: Send + Sync {
+    /// Opens a file for reading. The info describes the opened file itself,
+    /// so it can't disagree with the bytes that are read.
+    fn open(&self, name: &str) -> Result<(Box<dyn ReadSeek>, FileInfo), StorageError>;
+
+    fn stat(&self, name: &str) -> Result<FileInfo, StorageError> {
+        self.open(name).map(|(_, info)| info)
+    }
+
+    /// Creates or replaces a file in one step; readers see the old content
+    /// or the new, never a mix.
+    fn replace(&self, name: &str, contents: &[u8]) -> Result<(), StorageError>;
+
+    fn delete(&self, name: &str) -> Result<(), StorageError>;
+
+    /// Names of the entries directly inside a directory, sorted.
+    fn list(&self, dir: &str) -> Result<Vec<String>, StorageError>;
+}
+
+/// Files under a directory on the local filesystem.
+pub struct DirectoryStorage {
+    root: PathBuf,
+    follow_symlinks: bool,
+}
+
+impl DirectoryStorage {
+    /// `root` must already be canonical.
+    pub fn new(root: PathBuf, follow_symlinks: bool) -> Self {
+        Self {
+            root,
+            follow_symlinks,
+        }
+    }
+
+    fn resolve(&self, name: &str) -> Result<PathBuf, StorageError> {
+        // A missing root means the mount went away, not that the file is absent.
+        if !metadata(&self.root).is_ok_and(|meta| meta.is_dir()) {
+            return Err(StorageError::Unavailable);
+        }
+        // An upload in progress isn't a file yet, dotfiles served or not.
+        if is_upload_temp(name) {
+            return Err(StorageError::NotFound);
+        }
+        resolve_file(&self.root, name, self.follow_symlinks).ok_or(StorageError::Forbidden)
+    }
+
+    /// Writes need a name for the file itself. An empty name or a trailing
+    /// `/` resolves to a directory, and the upload's temporary file would
+    /// land next to it, outside the root for an empty name.
+    fn check_file_name(name: &str) -> Result<(), StorageError> {
+        if name.split('/').any(str::is_empty) || is_upload_temp(name) {
+            return Err(StorageError::Forbidden);
+        }
+        Ok(())
+    }
+}
+
+impl Storage for DirectoryStorage {
+    fn open(&self, name: &str) -> Result<(Box<dyn ReadSeek>, FileInfo), StorageError> {
+        let path = self.resolve(name)?;
+        let (file, metadata) = open_file(&path).ok_or(StorageError::NotFound)?;
+        Ok((Box::new(file), FileInfo::from(&metadata)))
+    }
+
+    fn replace(&self, name: &str, contents: &[u8]) -> Result<(), StorageError> {
+        Self::check_file_name(name)?;
+        let path = self.resolve(name)?;
+        write_atomically(&path, contents)?;
+        Ok(())
+    }
+
+    fn delete(&self, name: &str) -> Result<(), StorageError> {
+        Self::check_file_name(name)?;
+        let path = self.resolve(name)?;
+        if !symlink_metadata(&path).is_ok_and(|meta| !meta.is_dir()) {
+            return Err(StorageError::NotFound);
+        }
+        remove_file(path)?;
+        Ok(())
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<String>, StorageError> {
+        let path = if dir.is_empty() {
+            self.root.clone()
+        } else {
+            self.resolve(dir)?
+        };
+        let entries = read_dir(path).map_err(|err| match err.kind() {
+            ErrorKind::NotFound => StorageError::NotFound,
+            _ => StorageError::Io(err),
+        })?;
+        let mut names = Vec::new();
+        for entry in entries {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if !is_upload_temp(&name) {
+                names.push(name);
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+}
+
+struct StoredFile {
+    contents: Arc<[u8]>,
+    modified: SystemTime,
+}
+
+/// Files held in memory for the life of the process.
+#[derive(Default)]
+pub struct MemoryStorage {
+    files: RwLock<HashMap<String, StoredFile>>,
+}
+
+impl MemoryStorage {
+    /// Applies the same naming rule as the filesystem: plain segments only.
+    fn check_name(name: &str) -> Result<(), StorageError> {
+        if name
+            .split('/')
+            .any(|segment| segment.is_empty() || segment == "." || segment == "..")
+        {
+            return Err(StorageError::Forbidden);
+        }
+        Ok(())
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn open(&self, name: &str) -> Result<(Box<dyn ReadSeek>, FileInfo), StorageError> {
+        Self::check_name(name)?;
+        let files = self.files.read().unwrap();
+        let file = files.get(name).ok_or(StorageError::NotFound)?;
+        let info = FileInfo {
+            len: file.contents.len() as u64,
+            modified: Some(file.modified),
+            identity: None,
+        };
+        Ok((Box::new(Cursor::new(file.contents.clone())), info))
+    }
+
+    fn replace(&self, name: &str, contents: &[u8]) -> Result<(), StorageError> {
+        Self::check_name(name)?;
+        self.files.write().unwrap().insert(
+            name.to_owned(),
+            StoredFile {
+                contents: Arc::from(contents),
+                modified: SystemTime::now(),
+            },
+        );
+        Ok(())
+    }
+
+    fn delete(&self, name: &str) -> Result<(), StorageError> {
+        Self::check_name(name)?;
+        match self.files.write().unwrap().remove(name) {
+            Some(_) => Ok(()),
+            None => Err(StorageError::NotFound),
+        }
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<String>, StorageError> {
+        let prefix = if dir.is_empty() {
+            String::new()
+        } else {
+            Self::check_name(dir)?;
+            format!("{}/", dir)
+        };
+        let names: BTreeSet<String> = self
+            .files
+            .read()
+            .unwrap()
+            .keys()
+            .filter_map(|name| name.strip_prefix(&prefix))
+            .filter_map(|rest| rest.split('/').next())
+            .map(str::to_owned)
+            .collect();
+        if names.is_empty() && !prefix.is_empty() {
+            return Err(StorageError::NotFound);
+        }
+        Ok(names.into_iter().collect())
+    }
+}
+
+/// Another backend with every name that has a segment starting with `.`
+/// (`.git`, `.env`, `a/.htpasswd`) hidden, as if it didn't exist.
+pub struct HideDotfiles(pub Arc<dyn Storage>);
+
+impl HideDotfiles {
+    fn check_name(name: &str) -> Result<(), StorageError> {
+        if name.split('/').any(|segment| segment.starts_with('.')) {
+            return Err(StorageError::NotFound);
+        }
+        Ok(())
+    }
+}
+
+impl Storage for HideDotfiles {
+    fn open(&self, name: &str) -> Result<(Box<dyn ReadSeek>, FileInfo), StorageError> {
+        Self::check_name(name)?;
+        self.0.open(name)
+    }
+
+    fn stat(&self, name: &str) -> Result<FileInfo, StorageError> {
+        Self::check_name(name)?;
+        self.0.stat(name)
+    }
+
+    fn replace(&self, name: &str, contents: &[u8]) -> Result<(), StorageError> {
+        Self::check_name(name)?;
+        self.0.replace(name, contents)
+    }
+
+    fn delete(&self, name: &str) -> Result<(), StorageError> {
+        Self::check_name(name)?;
+        self.0.delete(name)
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<String>, StorageError> {
+        if !dir.is_empty() {
+            Self::check_name(dir)?;
+        }
+        let mut names = self.0.list(dir)?;
+        names.retain(|name| !name.starts_with('.'));
+        Ok(names)
+    }
+}
+
+const TAR_BLOCK: u64 = 512;
+
+struct TarEntry {
+    offset: u64,
+    len: u64,
+    modified: SystemTime,
+}
+
+/// Regular files inside a tar archive, served in place. Only the index of
+/// entries is kept in memory; reads seek into the archive itself.
+pub struct TarStorage {
+    path: PathBuf,
+    /// Size and mtime of the archive when it was indexed.
+    archive: (u64, Option<SystemTime>),
+    entries: HashMap<String, TarEntry>,
+}
+
+impl TarStorage {
+    /// Reads every header in the archive to build the index. Understands
+    /// ustar, GNU long names and the `path`/`size`/`mtime` pax records;
+    /// links and other special entries are left out.
+    pub fn open_archive(path: PathBuf) -> io::Result<Self> {
+        let mut file = File::open(&path)?;
+        let metadata = file.metadata()?;
+        let invalid = |message: &str| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), message),
+            )
+        };
+
+        let mut entries = HashMap::new();
+        let mut offset = 0;
+        let mut long_name = None;
+        let mut pax = HashMap::new();
+        let mut header = [0u8; TAR_BLOCK as usize];
+        loop {
+            file.seek(SeekFrom::Start(offset))?;
+            if file.read_exact(&mut header).is_err() || header.iter().all(|&b| b == 0) {
+                break;
+            }
+            if Some(tar_checksum(&header)) != parse_octal(&header[148..156]) {
+                return Err(invalid("bad header checksum"));
+            }
+            let data_offset = offset + TAR_BLOCK;
+            let mut len = parse_octal(&header[124..136]).ok_or_else(|| invalid("bad size"))?;
+            let mut mtime = parse_octal(&header[136..148]).unwrap_or(0);
+            let type_flag = header[156];
+
+            if matches!(type_flag, b'L' | b'x') {
+                if len > TAR_BLOCK * 128 {
+                    return Err(invalid("oversized extended header"));
+                }
+                let mut data = vec![0; len as usize];
+                file.read_exact(&mut data)?;
+                if type_flag == b'L' {
+                    long_name = Some(String::from_utf8_lossy(nul_terminated(&data)).into_owned());
+                } else {
+                    pax = parse_pax(&data);
+                }
+            } else {
+                let name = long_name
+                    .take()
+                    .or_else(|| pax.remove("path"))
+                    .unwrap_or_else(|| {
+                        let name = String::from_utf8_lossy(nul_terminated(&header[..100]));
+                        let prefix = String::from_utf8_lossy(nul_terminated(&header[345..500]));
+                        if &header[257..262] == b"ustar" && !prefix.is_empty() {
+                            format!("{}/{}", prefix, name)
+                        } else {
+                            name.into_owned()
+                        }
+                    });
+                if let Some(size) = pax.remove("size").and_then(|size| size.parse().ok()) {
+                    len = size;
+                }
+                if let Some(secs) = pax
+                    .remove("mtime")
+                    .and_then(|secs| secs.split('.').next().and_then(|secs| secs.parse().ok()))
+                {
+                    mtime = secs;
+                }
+                pax.clear();
+
+                let name = name.trim_start_matches("./").trim_matches('/');
+                let plain = name
+                    .split('/')
+                    .all(|segment| !segment.is_empty() && segment != "." && segment != "..");
+                if matches!(type_flag, b'0' | b'\0' | b'7') && plain {
+                    // Base-256 and pax values can go far past what
+                    // SystemTime holds.
+                    let modified = UNIX_EPOCH
+                        .checked_add(Duration::from_secs(mtime))
+                        .ok_or_else(|| invalid("bad mtime"))?;
+                    entries.insert(
+                        name.to_owned(),
+                        TarEntry {
+                            offset: data_offset,
+                            len,
+                            modified,
+                        },
+                    );
+                }
+            }
+            offset = len
+                .div_ceil(TAR_BLOCK)
+                .checked_mul(TAR_BLOCK)
+                .and_then(|padded| data_offset.checked_add(padded))
+                .ok_or_else(|| invalid("bad size"))?;
+        }
+
+        Ok(Self {
+            path,
+            archive: (metadata.len(), metadata.modified().ok()),
+            entries,
+        })
+    }
+}
+
+impl Storage for TarStorage {
+    fn open(&self, name: &str) -> Result<(Box<dyn ReadSeek>, FileInfo), StorageError> {
+        let entry = self.entries.get(name).ok_or(StorageError::NotFound)?;
+        let mut file = File::open(&self.path).map_err(|_| StorageError::Unavailable)?;
+        // The offsets are only good for the archive that was indexed.
+        let metadata = file.metadata()?;
+        if (metadata.len(), metadata.modified().ok()) != self.archive {
+            return Err(StorageError::Unavailable);
+        }
+        file.seek(SeekFrom::Start(entry.offset))?;
+
+        let mut hasher = DefaultHasher::new();
+        (self.archive, entry.offset).hash(&mut hasher);
+        let info = FileInfo {
+            len: entry.len,
+            modified: Some(entry.modified),
+            identity: Some(hasher.finish()),
+        };
+        let section = Section {
+            inner: file,
+            start: entry.offset,
+            len: entry.len,
+            pos: 0,
+        };
+        Ok((Box::new(section), info))
+    }
+
+    fn replace(&self, _name: &str, _contents: &[u8]) -> Result<(), StorageError> {
+        Err(StorageError::ReadOnly)
+    }
+
+    fn delete(&self, _name: &str) -> Result<(), StorageError> {
+        Err(StorageError::ReadOnly)
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<String>, StorageError> {
+        let prefix = if dir.is_empty() {
+            String::new()
+        } else {
+            format!("{}/", dir)
+        };
+        let names: BTreeSet<String> = self
+            .entries
+            .keys()
+            .filter_map(|name| name.strip_prefix(&prefix))
+            .filter_map(|rest| rest.split('/').next())
+            .map(str::to_owned)
+            .collect();
+        if names.is_empty() && !prefix.is_empty() {
+            return Err(StorageError::NotFound);
+        }
+        Ok(names.into_iter().collect())
+    }
+}
+
+/// The `len` bytes of `inner` starting at `start`, seekable as if they
+/// were a file of their own. `inner` must already be positioned at `start`.
+struct Section<R> {
+    inner: R,
+    start: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl<R: Read> Read for Section<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.len.saturating_sub(self.pos);
+        let limit = buf
+            .len()
+            .min(usize::try_from(remaining).unwrap_or(usize::MAX));
+        let read = self.inner.read(&mut buf[..limit])?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: Seek> Seek for Section<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.len.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        }
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "seek before start of entry"))?;
+        self.inner.seek(SeekFrom::Start(self.start + target))?;
+        self.pos = target;
+        Ok(target)
+    }
+}
+
+/// The header checksum: the sum of all bytes with the checksum field itself
+/// read as spaces.
+fn tar_checksum(header: &[u8]) -> u64 {
+    header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| u64::from(if (148..156).contains(&i) { b' ' } else { b }))
+        .sum()
+}
+
+/// Reads a numeric header field: NUL/space padded octal, or big-endian
+/// binary when the high bit of the first byte is set (GNU, for large sizes).
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    if field.first().is_some_and(|&b| b & 0x80 != 0) {
+        return field[1..]
+            .iter()
+            .try_fold(u64::from(field[0] & 0x7f), |value, &b| {
+                value.checked_mul(256).map(|value| value + u64::from(b))
+            });
+    }
+    let digits = std::str::from_utf8(nul_terminated(field)).ok()?.trim();
+    if digits.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(digits, 8).ok()
+}
+
+fn nul_terminated(bytes: &[u8]) -> &[u8] {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    &bytes[..end]
+}
+
+/// Parses pax extended header records, each `<len> <key>=<value>\n`.
+fn parse_pax(data: &[u8]) -> HashMap<String, String> {
+    let mut records = HashMap::new();
+    let mut rest = data;
+    while let Some(space) = rest.iter().position(|&b| b == b' ') {
+        let Some(len) = std::str::from_utf8(&rest[..space])
+            .ok()
+            .and_then(|len| len.parse::<usize>().ok())
+            .filter(|&len| len > space && len <= rest.len())
+        else {
+            break;
+        };
+        let record = String::from_utf8_lossy(&rest[space + 1..len]);
+        if let Some((key, value)) = record.trim_end_matches('\n').split_once('=') {
+            records.insert(key.to_owned(), value.to_owned());
+        }
+        rest = &rest[len..];
+    }
+    records
+}
+
+/// Opens a regular file and returns it with its metadata, taken from the
+/// opened handle so both describe the same file.
+fn open_file(path: &Path) -> Option<(File, Metadata)> {
+    let file = File::open(path).ok()?;
+    let metadata = file.metadata().ok()?;
+    metadata.is_file().then_some((file, metadata))
+}
+
+/// Resolves a request-supplied name against the canonical served root.
+/// Only plain path segments are accepted, and the result is canonicalized
+/// so a symlink can't lead outside `root`. For paths that don't exist yet
+/// (uploads) the nearest existing ancestor is canonicalized instead. With
+/// `follow_symlinks` off, any symlink along the way is refused outright.
+fn resolve_file(root: &Path, name: &str, follow_symlinks: bool) -> Option<PathBuf> {
+    let name = Path::new(name);
+    if !name
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return None;
+    }
+
+    let path = root.join(name);
+    if !follow_symlinks {
+        let mut current = root.to_path_buf();
+        for component in name.components() {
+            current.push(component);
+            if symlink_metadata(&current).is_ok_and(|meta| meta.file_type().is_symlink()) {
+                return None;
+            }
+        }
+    }
+
+    let mut existing = path.as_path();
+    let mut missing = Vec::new();
+    let resolved = loop {
+        match existing.canonicalize() {
+            Ok(canonical) => {
+                break missing
+                    .iter()
+                    .rev()
+                    .fold(canonical, |resolved, part| resolved.join(part));
+            }
+            Err(_) => {
+                missing.push(existing.file_name()?);
+                existing = existing.parent()?;
+            }
+        }
+    };
+
+    resolved.starts_with(root).then_some(resolved)
+}
+
+const UPLOAD_SUFFIX: &str = ".upload";
+
+/// Whether `name` ends in a temporary file [`write_atomically`] creates.
+fn is_upload_temp(name: &str) -> bool {
+    let file_name = name.rsplit('/').next().unwrap_or(name);
+    file_name.starts_with('.') && file_name.ends_with(UPLOAD_SUFFIX)
+}
+
+/// Writes `contents` next to `path` and renames it into place, so readers
+/// see the old file or the new one but never a mix. A GET that already
+/// opened the old file keeps streaming that inode to the end.
+fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    static NEXT_UPLOAD: AtomicU64 = AtomicU64::new(0);
+
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "upload target has no file name"))?;
+    let temp_path = path.with_file_name(format!(
+        ".{}.{}-{}{}",
+        file_name.to_string_lossy(),
+        process::id(),
+        NEXT_UPLOAD.fetch_add(1, Ordering::Relaxed),
+        UPLOAD_SUFFIX
+    ));
+    let result = write(&temp_path, contents).and_then(|_| rename(&temp_path, path));
+    if result.is_err() {
+        let _ = remove_file(&temp_path);
+    }
+    result
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::fs::{create_dir_all, read, remove_dir_all, OpenOptions};
+    use std::io::Write;
+
+    /// A fresh directory under the system temp dir, removed on drop.
+    pub(crate) struct TempDir(pub(crate) PathBuf);
+
+    impl TempDir {
+        pub(crate) fn new(label: &str) -> Self {
+            static NEXT: AtomicU64 = AtomicU64::new(0);
+            let path = std::env::temp_dir().join(format!(
+                "http-storage-{}-{}-{}",
+                label,
+                process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            ));
+            create_dir_all(&path).unwrap();
+            Self(path.canonicalize().unwrap())
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = remove_dir_all(&self.0);
+        }
+    }
+
+    fn tar_header(name: &str, len: usize, type_flag: u8) -> [u8; TAR_BLOCK as usize] {
+        let mut header = [0u8; TAR_BLOCK as usize];
+        let name = &name.as_bytes()[..name.len().min(100)];
+        header[..name.len()].copy_from_slice(name);
+        header[100..108].copy_from_slice(b"0000644\0");
+        header[124..136].copy_from_slice(format!("{:011o}\0", len).as_bytes());
+        header[136..148].copy_from_slice(format!("{:011o}\0", 1_700_000_000).as_bytes());
+        header[156] = type_flag;
+        header[257..265].copy_from_slice(b"ustar\x0000");
+        let checksum = tar_checksum(&header);
+        header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+        header
+    }
+
+    /// A ustar archive of `(name, type flag, contents)` entries. Names over
+    /// 100 bytes get a GNU long-name entry first.
+    pub(crate) fn tar_archive(entries: &[(&str, u8, &[u8])]) -> Vec<u8> {
+        let mut archive = Vec::new();
+        let push = |archive: &mut Vec<u8>, name: &str, type_flag: u8, data: &[u8]| {
+            archive.extend_from_slice(&tar_header(name, data.len(), type_flag));
+            archive.extend_from_slice(data);
+            archive.resize(archive.len().next_multiple_of(TAR_BLOCK as usize), 0);
+        };
+        for (name, type_flag, data) in entries {
+            if name.len() > 100 {
+                let long_name = format!("{}\0", name);
+                push(&mut archive, "././@LongLink", b'L', long_name.as_bytes());
+            }
+            push(&mut archive, name, *type_flag, data);
+        }
+        archive.resize(archive.len() + 2 * TAR_BLOCK as usize, 0);
+        archive
+    }
+
+    fn read_all(storage: &dyn Storage, name: &str) -> Vec<u8> {
+        let (mut reader, _) = storage.open(name).unwrap();
+        let mut contents = Vec::new();
+        reader.read_to_end(&mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn directory_writes_need_a_file_name() {
+        let dir = TempDir::new("names");
+        let root = dir.0.join("root");
+        create_dir_all(&root).unwrap();
+        let storage = DirectoryStorage::new(root.clone(), false);
+
+        for name in ["", "sub/", "a//b"] {
+            assert!(
+                matches!(storage.replace(name, b"x"), Err(StorageError::Forbidden)),
+                "{:?}",
+                name
+            );
+            assert!(
+                matches!(storage.delete(name), Err(StorageError::Forbidden)),
+                "{:?}",
+                name
+            );
+        }
+        // Nothing was written beside the root or inside it.
+        assert_eq!(read_dir(&dir.0).unwrap().count(), 1);
+        assert_eq!(read_dir(&root).unwrap().count(), 0);
+
+        storage.replace("sub.txt", b"hello").unwrap();
+        assert_eq!(read(root.join("sub.txt")).unwrap(), b"hello");
+        assert_eq!(read_all(&storage, "sub.txt"), b"hello");
+        storage.delete("sub.txt").unwrap();
+        assert!(matches!(
+            storage.open("sub.txt"),
+            Err(StorageError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn concurrent_reads_see_one_whole_upload() {
+        let dir = TempDir::new("atomic");
+        let storage = Arc::new(DirectoryStorage::new(dir.0.clone(), false));
+        let payloads = [vec![b'a'; 64 * 1024], vec![b'b'; 100 * 1024]];
+        storage.replace("file", &payloads[0]).unwrap();
+
+        std::thread::scope(|scope| {
+            let writer = {
+                let storage = storage.clone();
+                let payloads = &payloads;
+                scope.spawn(move || {
+                    for round in 0..200 {
+                        storage.replace("file", &payloads[round % 2]).unwrap();
+                    }
+                })
+            };
+            for _ in 0..8 {
+                let storage = storage.clone();
+                scope.spawn(move || {
+                    for _ in 0..200 {
+                        let (mut reader, info) = storage.open("file").unwrap();
+                        let mut contents = Vec::new();
+                        reader.read_to_end(&mut contents).unwrap();
+                        assert_eq!(contents.len() as u64, info.len);
+                        assert!(
+                            contents.iter().all(|&b| b == contents[0]),
+                            "read a mix of two uploads"
+                        );
+                    }
+                });
+            }
+            writer.join().unwrap();
+        });
+        // No temporary upload was left behind.
+        assert_eq!(read_dir(&dir.0).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn uploads_in_progress_are_not_served() {
+        let dir = TempDir::new("upload-temp");
+        let storage = DirectoryStorage::new(dir.0.clone(), false);
+        let temp = ".file.42-0.upload";
+        write(dir.0.join(temp), b"partial").unwrap();
+        storage.replace("file", b"whole").unwrap();
+
+        assert!(matches!(storage.open(temp), Err(StorageError::NotFound)));
+        assert!(matches!(
+            storage.replace(temp, b"x"),
+            Err(StorageError::Forbidden)
+        ));
+        assert!(matches!(storage.delete(temp), Err(StorageError::Forbidden)));
+        assert_eq!(storage.list("").unwrap(), ["file"]);
+        assert_eq!(read(dir.0.join(temp)).unwrap(), b"partial");
+        // Only the temporary naming pattern is hidden.
+        storage.replace("notes.upload", b"kept").unwrap();
+        assert_eq!(read_all(&storage, "notes.upload"), b"kept");
+    }
+
+    #[test]
+    fn serves_nested_tar_entries() {
+        let dir = TempDir::new("tar");
+        let binary: Vec<u8> = (0..=255).cycle().take(768).collect();
+        let long_name = format!("long/{}.txt", "x".repeat(120));
+        let archive = dir.0.join("site.tar");
+        write(
+            &archive,
+            tar_archive(&[
+                ("docs/", b'5', b""),
+                ("docs/readme.txt", b'0', b"hello tar"),
+                ("./nested/deep/file.bin", b'0', &binary),
+                ("link", b'2', b""),
+                ("../escape", b'0', b"no"),
+                (&long_name, b'0', b"long"),
+            ]),
+        )
+        .unwrap();
+        let storage = TarStorage::open_archive(archive.clone()).unwrap();
+
+        assert_eq!(read_all(&storage, "docs/readme.txt"), b"hello tar");
+        assert_eq!(read_all(&storage, "nested/deep/file.bin"), binary);
+        assert_eq!(read_all(&storage, &long_name), b"long");
+        assert_eq!(storage.list("").unwrap(), ["docs", "long", "nested"]);
+        assert_eq!(storage.list("nested").unwrap(), ["deep"]);
+        for name in ["docs", "link", "../escape", "escape", "missing"] {
+            assert!(
+                matches!(storage.open(name), Err(StorageError::NotFound)),
+                "{}",
+                name
+            );
+        }
+
+        // Each entry seeks and ends on its own, without reaching into the
+        // next header.
+        let (mut reader, info) = storage.open("nested/deep/file.bin").unwrap();
+        assert_eq!(info.len, 768);
+        let mut buf = [0; 5];
+        reader.seek(SeekFrom::Start(510)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, binary[510..515]);
+        reader.seek(SeekFrom::End(-3)).unwrap();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, binary[765..]);
+        assert!(reader.seek(SeekFrom::Current(-1000)).is_err());
+    }
+
+    #[test]
+    fn tar_storage_is_read_only_and_notices_a_changed_archive() {
+        let dir = TempDir::new("tar-ro");
+        let archive = dir.0.join("site.tar");
+        write(&archive, tar_archive(&[("a.txt", b'0', b"a")])).unwrap();
+        let storage = TarStorage::open_archive(archive.clone()).unwrap();
+
+        assert!(matches!(
+            storage.replace("a.txt", b"b"),
+            Err(StorageError::ReadOnly)
+        ));
+        assert!(matches!(
+            storage.delete("a.txt"),
+            Err(StorageError::ReadOnly)
+        ));
+        assert_eq!(read_all(&storage, "a.txt"), b"a");
+
+        OpenOptions::new()
+            .append(true)
+            .open(&archive)
+            .unwrap()
+            .write_all(&[0; TAR_BLOCK as usize])
+            .unwrap();
+        assert!(matches!(
+            storage.open("a.txt"),
+            Err(StorageError::Unavailable)
+        ));
+    }
+
+    #[test]
+    fn refuses_a_corrupt_archive() {
+        let dir = TempDir::new("tar-bad");
+        let archive = dir.0.join("bad.tar");
+        let mut bytes = tar_archive(&[("a.txt", b'0', b"a")]);
+        bytes[0] = b'b';
+        write(&archive, bytes).unwrap();
+        let err = TarStorage::open_archive(archive).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn refuses_sizes_and_mtimes_past_their_range() {
+        let dir = TempDir::new("tar-huge");
+        let archive = dir.0.join("huge.tar");
+        // Base-256 u64::MAX: the size overflows the offset arithmetic and
+        // the mtime overflows SystemTime.
+        let mut huge = [0xff; 12];
+        huge[..4].copy_from_slice(&[0x80, 0, 0, 0]);
+        for field in [124..136, 136..148] {
+            let mut bytes = tar_archive(&[("a.txt", b'0', b"a")]);
+            bytes[field].copy_from_slice(&huge);
+            let checksum = tar_checksum(&bytes[..TAR_BLOCK as usize]);
+            bytes[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+            write(&archive, bytes).unwrap();
+            let err = TarStorage::open_archive(archive.clone()).err().unwrap();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+
+        let record = format!("30 mtime={}\n", u64::MAX);
+        assert_eq!(record.len(), 30);
+        let bytes = tar_archive(&[("pax", b'x', record.as_bytes()), ("a.txt", b'0', b"a")]);
+        write(&archive, bytes).unwrap();
+        let err = TarStorage::open_archive(archive).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn symlinks_cannot_lead_outside_the_root() {
+        use std::os::unix::fs::symlink;
+
+        let dir = TempDir::new("symlinks");
+        let root = dir.0.join("root");
+        create_dir_all(root.join("sub")).unwrap();
+        write(dir.0.join("secret.txt"), b"secret").unwrap();
+        write(root.join("sub/inside.txt"), b"inside").unwrap();
+        symlink(dir.0.join("secret.txt"), root.join("escape.txt")).unwrap();
+        symlink(&dir.0, root.join("parent")).unwrap();
+        symlink(root.join("sub"), root.join("alias")).unwrap();
+
+        let following = DirectoryStorage::new(root.clone(), true);
+        for name in ["escape.txt", "parent/secret.txt", "parent/new.txt"] {
+            assert!(
+                matches!(following.open(name), Err(StorageError::Forbidden)),
+                "{}",
+                name
+            );
+        }
+        assert!(matches!(
+            following.replace("parent/new.txt", b"x"),
+            Err(StorageError::Forbidden)
+        ));
+        assert!(!dir.0.join("new.txt").exists());
+        // A link that stays inside the root is fine when following.
+        assert_eq!(read_all(&following, "alias/inside.txt"), b"inside");
+
+        let strict = DirectoryStorage::new(root.clone(), false);
+        assert!(matches!(
+            strict.open("alias/inside.txt"),
+            Err(StorageError::Forbidden)
+        ));
+        assert_eq!(read_all(&strict, "sub/inside.txt"), b"inside");
+        // Uploads into a directory that doesn't exist yet stay inside too.
+        assert!(matches!(
+            strict.replace("missing/../../x", b"x"),
+            Err(StorageError::Forbidden)
+        ));
+    }
+
+    #[test]
+    fn hide_dotfiles_treats_them_as_missing() {
+        let inner = Arc::new(MemoryStorage::default());
+        for name in [".env", "a/.htpasswd", "a/visible.txt", "top.txt"] {
+            inner.replace(name, b"x").unwrap();
+        }
+        let storage = HideDotfiles(inner);
+        for name in [".env", "a/.htpasswd", ".git/config"] {
+            assert!(
+                matches!(storage.open(name), Err(StorageError::NotFound)),
+                "{}",
+                name
+            );
+            assert!(matches!(storage.stat(name), Err(StorageError::NotFound)));
+            assert!(matches!(
+                storage.replace(name, b"y"),
+                Err(StorageError::NotFound)
+            ));
+            assert!(matches!(storage.delete(name), Err(StorageError::NotFound)));
+        }
+        assert_eq!(read_all(&storage, "a/visible.txt"), b"x");
+        assert_eq!(storage.list("").unwrap(), ["a", "top.txt"]);
+        assert_eq!(storage.list("a").unwrap(), ["visible.txt"]);
+    }
+
+    #[test]
+    fn directory_refuses_traversal() {
+        let dir = TempDir::new("traversal");
+        let storage = DirectoryStorage::new(dir.0.clone(), false);
+        for name in ["../etc/passwd", "a/../../x", "/etc/passwd", "./x"] {
+            assert!(
+                matches!(storage.open(name), Err(StorageError::Forbidden)),
+                "{:?}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn directory_reports_a_missing_root_as_unavailable() {
+        let dir = TempDir::new("missing-root");
+        let root = dir.0.join("root");
+        create_dir_all(&root).unwrap();
+        let storage = DirectoryStorage::new(root.clone(), false);
+        assert!(matches!(storage.open("a.txt"), Err(StorageError::NotFound)));
+
+        remove_dir_all(&root).unwrap();
+        assert!(matches!(
+            storage.open("a.txt"),
+            Err(StorageError::Unavailable)
+        ));
+    }
+}